@@ -3,6 +3,11 @@ use std::io::Result;
 fn main() -> Result<()> {
     tonic_build::configure()
         .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
+        .type_attribute(".biotracker.ProcessConfig", "#[serde(default)]")
+        .field_attribute(".biotracker.Track.entity_events", "#[serde(default)]")
+        .field_attribute(".biotracker.Features.ambiguous", "#[serde(default)]")
+        .field_attribute(".biotracker.Feature.merged_ids", "#[serde(default)]")
+        .field_attribute(".biotracker.Feature.embedding", "#[serde(default)]")
         .field_attribute(
             ".biotracker.ComponentConfig.config_json",
             "#[serde(deserialize_with=\"from_map\", serialize_with=\"to_map\")]",
@@ -37,6 +37,10 @@ class SortMatcher(MatcherBase):
         self.tracker = Sort(max_age=50, min_hits=3)
         return Empty()
 
+    async def reset(self, empty: "Empty") -> "Empty":
+        self.tracker = Sort(max_age=50, min_hits=3)
+        return Empty()
+
 async def main():
     heartbeat()
     addr, port = get_address_and_port()
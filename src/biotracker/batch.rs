@@ -0,0 +1,300 @@
+use super::protocol::Arena;
+use crate::util::framenumber_to_hhmmss;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+
+/// File extensions which are picked up when a directory is used as batch queue.
+const VIDEO_EXTENSIONS: [&str; 7] = ["mp4", "avi", "mkv", "mov", "m4v", "mpg", "webm"];
+
+/// Progress of an offline batch run. Tracking results are counted here and reported on stderr,
+/// so that batch runs can be monitored from shell scripts and job schedulers.
+pub struct BatchProgress {
+    frame_count: u32,
+    frames_tracked: u32,
+    frames_failed: u32,
+    frames_dropped: u32,
+    start: std::time::Instant,
+    last_report: std::time::Instant,
+}
+
+impl BatchProgress {
+    pub fn new(frame_count: u32) -> Self {
+        let now = std::time::Instant::now();
+        Self {
+            frame_count,
+            frames_tracked: 0,
+            frames_failed: 0,
+            frames_dropped: 0,
+            start: now,
+            last_report: now,
+        }
+    }
+
+    pub fn frame_tracked(&mut self) {
+        self.frames_tracked += 1;
+        self.report(false);
+    }
+
+    pub fn frame_failed(&mut self) {
+        self.frames_failed += 1;
+        self.report(false);
+    }
+
+    /// Videos without a known frame count end with a decoding error, which is not counted as a
+    /// failure.
+    pub fn decoding_failed(&mut self) {
+        if self.frame_count > 0 {
+            self.frame_failed();
+        }
+    }
+
+    /// Frames which were decoded, but could not be passed to tracking.
+    pub fn frame_dropped(&mut self) {
+        self.frames_dropped += 1;
+        self.report(false);
+    }
+
+    pub fn frames_done(&self) -> u32 {
+        self.frames_tracked + self.frames_failed + self.frames_dropped
+    }
+
+    /// Print a progress line to stderr. Unless forced, this happens at most once per second.
+    pub fn report(&mut self, force: bool) {
+        if !force && self.last_report.elapsed().as_secs() < 1 {
+            return;
+        }
+        self.last_report = std::time::Instant::now();
+        let frames_done = self.frames_done();
+        let fps = frames_done as f64 / self.start.elapsed().as_secs_f64();
+        let mut line = format!(
+            "{} frames tracked, {} failed, {} dropped, {:.1} frames/s",
+            self.frames_tracked, self.frames_failed, self.frames_dropped, fps
+        );
+        if self.frame_count > 0 {
+            let remaining = self.frame_count.saturating_sub(frames_done);
+            let percent = 100.0 * frames_done as f64 / self.frame_count as f64;
+            line = format!(
+                "{}/{} ({:.1}%): {}",
+                frames_done, self.frame_count, percent, line
+            );
+            if fps > 0.0 {
+                line = format!("{}, ETA {}", line, framenumber_to_hhmmss(remaining, fps));
+            }
+        }
+        eprintln!("{}", line);
+    }
+}
+
+/// Per-video settings for batch runs. They are read from a sidecar file next to the video, e.g.
+/// `video.batch.json` for `video.mp4`.
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct BatchOverrides {
+    /// Replaces the arena of the configuration file
+    pub arena: Option<Arena>,
+    /// Id of the camera configuration which is used for undistortion, see --force-camera-config
+    pub camera_config: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct BatchItem {
+    pub video: String,
+    pub output: PathBuf,
+    /// File with per-video overrides, read when the video is opened
+    pub sidecar: Option<PathBuf>,
+}
+
+impl BatchItem {
+    /// Create a batch item for a video file. The track is saved next to the video.
+    pub fn from_video(video: PathBuf) -> Self {
+        Self {
+            video: video.to_string_lossy().to_string(),
+            output: video.with_extension("json"),
+            sidecar: Some(video.with_extension("batch.json")),
+        }
+    }
+
+    /// Overrides of the sidecar file, if it exists. A malformed sidecar fails only its own video.
+    pub fn overrides(&self) -> Result<BatchOverrides> {
+        let sidecar = match &self.sidecar {
+            Some(sidecar) if sidecar.exists() => sidecar,
+            _ => return Ok(BatchOverrides::default()),
+        };
+        let file = std::fs::File::open(sidecar)
+            .with_context(|| format!("Failed to open sidecar file {}", sidecar.display()))?;
+        let reader = std::io::BufReader::new(file);
+        serde_json::from_reader(reader)
+            .with_context(|| format!("Invalid sidecar file {}", sidecar.display()))
+    }
+}
+
+/// Result of tracking a single video in a batch run.
+#[derive(Serialize, Debug)]
+pub struct BatchSummary {
+    pub video: String,
+    pub track: String,
+    pub frames_tracked: u32,
+    pub frames_dropped: u32,
+    pub frames_failed: u32,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct BatchReport<'a> {
+    videos: &'a Vec<BatchSummary>,
+}
+
+/// Videos of a batch run, which are opened and tracked one after another.
+pub struct BatchRun {
+    queue: VecDeque<BatchItem>,
+    current: Option<(BatchItem, BatchProgress)>,
+    summaries: Vec<BatchSummary>,
+    /// Arena of the configuration file, restored for videos without arena override
+    pub default_arena: Option<Arena>,
+}
+
+impl BatchRun {
+    pub fn new(items: Vec<BatchItem>, default_arena: Option<Arena>) -> Self {
+        Self {
+            queue: items.into(),
+            current: None,
+            summaries: vec![],
+            default_arena,
+        }
+    }
+
+    pub fn next_item(&mut self) -> Option<BatchItem> {
+        self.queue.pop_front()
+    }
+
+    pub fn start(&mut self, item: BatchItem, frame_count: u32) {
+        eprintln!("Tracking {}", item.video);
+        self.current = Some((item, BatchProgress::new(frame_count)));
+    }
+
+    /// Finish the current video. Returns the item, so that its track can be saved.
+    pub fn finish(&mut self) -> Option<BatchItem> {
+        let (item, mut progress) = self.current.take()?;
+        progress.report(true);
+        self.summaries.push(BatchSummary {
+            video: item.video.clone(),
+            track: item.output.to_string_lossy().to_string(),
+            frames_tracked: progress.frames_tracked,
+            frames_dropped: progress.frames_dropped,
+            frames_failed: progress.frames_failed,
+            error: None,
+        });
+        Some(item)
+    }
+
+    /// Record an error for a video, which was finished or could not be opened.
+    pub fn failed(&mut self, item: &BatchItem, error: &anyhow::Error) {
+        log::error!("Batch processing of {} failed: {}", item.video, error);
+        match self.summaries.iter_mut().find(|s| s.video == item.video) {
+            Some(summary) => summary.error = Some(error.to_string()),
+            None => self.summaries.push(BatchSummary {
+                video: item.video.clone(),
+                track: item.output.to_string_lossy().to_string(),
+                frames_tracked: 0,
+                frames_dropped: 0,
+                frames_failed: 0,
+                error: Some(error.to_string()),
+            }),
+        }
+    }
+
+    pub fn progress(&mut self) -> Option<&mut BatchProgress> {
+        self.current.as_mut().map(|(_, progress)| progress)
+    }
+
+    pub fn frame_tracked(&mut self) {
+        if let Some(progress) = self.progress() {
+            progress.frame_tracked();
+        }
+    }
+
+    pub fn frame_failed(&mut self) {
+        if let Some(progress) = self.progress() {
+            progress.frame_failed();
+        }
+    }
+
+    pub fn decoding_failed(&mut self) {
+        if let Some(progress) = self.progress() {
+            progress.decoding_failed();
+        }
+    }
+
+    pub fn frame_dropped(&mut self) {
+        if let Some(progress) = self.progress() {
+            progress.frame_dropped();
+        }
+    }
+
+    /// Number of videos, which failed or contain frames that failed to track or were dropped.
+    pub fn failed_videos(&self) -> usize {
+        self.summaries
+            .iter()
+            .filter(|s| s.error.is_some() || s.frames_failed > 0 || s.frames_dropped > 0)
+            .count()
+    }
+
+    pub fn write_report(&self, path: &Path) -> Result<()> {
+        let file = std::fs::File::create(path)?;
+        let writer = std::io::BufWriter::new(file);
+        serde_json::to_writer_pretty(
+            writer,
+            &BatchReport {
+                videos: &self.summaries,
+            },
+        )?;
+        Ok(())
+    }
+
+    pub fn print_summary(&self) {
+        for summary in &self.summaries {
+            eprintln!(
+                "{}: {} frames tracked, {} dropped, {} failed{}",
+                summary.video,
+                summary.frames_tracked,
+                summary.frames_dropped,
+                summary.frames_failed,
+                match &summary.error {
+                    Some(e) => format!(", error: {}", e),
+                    None => "".to_owned(),
+                }
+            );
+        }
+    }
+}
+
+/// Read a batch queue. It is either a directory containing videos, or a text file listing one
+/// video per line. Relative paths in the text file are relative to its own location.
+pub fn read_queue(path: &Path) -> Result<Vec<BatchItem>> {
+    let mut videos = vec![];
+    if path.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            let video = entry?.path();
+            let is_video = match video.extension().and_then(|e| e.to_str()) {
+                Some(extension) => VIDEO_EXTENSIONS.contains(&extension.to_lowercase().as_str()),
+                None => false,
+            };
+            if is_video {
+                videos.push(video);
+            }
+        }
+        videos.sort();
+    } else {
+        let base = path.parent().unwrap_or(Path::new("."));
+        let list = std::fs::read_to_string(path)?;
+        for line in list.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            videos.push(base.join(line));
+        }
+    }
+    Ok(videos.into_iter().map(BatchItem::from_video).collect())
+}
@@ -1,8 +1,16 @@
 use super::{
-    protocol::*, tracking::start_tracking_task, BiotrackerConfig, ChannelRequest,
-    CommandLineArguments, Service, State,
+    batch::{read_queue, BatchItem, BatchRun},
+    entity_lifecycle::EntityLifecycle,
+    playback::PlaybackClock,
+    protocol::*,
+    subscription::StatePublisher,
+    tracking::{start_tracking_task, TrackingPipeline},
+    BiotrackerConfig, ChannelRequest, CommandLineArguments, Service, State,
+};
+use crate::{
+    biotracker::{component::ComponentError, observer::start_observer_task},
+    log_error,
 };
-use crate::{biotracker::observer::start_observer_task, log_error};
 use anyhow::{Context, Result};
 use bio_tracker_server::BioTrackerServer;
 use metrics::{describe_counter, describe_histogram};
@@ -19,20 +27,25 @@ pub struct Core {
     image_rx: Receiver<ChannelRequest<Image, Result<Empty>>>,
     state: State,
     state_rx: Receiver<ChannelRequest<(), Experiment>>,
+    batch: Option<BatchRun>,
+    publisher: Arc<StatePublisher>,
 }
 
 impl Core {
     pub async fn new(args: &CommandLineArguments, config: BiotrackerConfig) -> Result<Self> {
         let args = Arc::new(args.clone());
-        let state = State::new(config);
+        let mut state = State::new(config);
+        state.tracking_pipeline_depth = args.tracking_pipeline_depth as usize;
         let (command_tx, command_rx) = channel(1);
         let (state_tx, state_rx) = channel(1);
         let (image_tx, image_rx) = channel(1);
+        let publisher = Arc::new(StatePublisher::new());
 
         let biotracker_server = BioTrackerServer::new(Service {
             command_tx,
             state_tx,
             image_tx,
+            publisher: publisher.clone(),
         });
         let address = format!("127.0.0.1:{}", args.port).parse()?;
         tokio::spawn(async move {
@@ -53,6 +66,8 @@ impl Core {
             image_rx,
             state,
             state_rx,
+            batch: None,
+            publisher,
         })
     }
 
@@ -64,13 +79,14 @@ impl Core {
             .start_components(components, port_range_start)
             .await?;
 
+        self.state.verify_seeks = self.args.verify_seeks;
         if let Some(video) = self.args.video.clone() {
             log_error!(self.state.open_video(video, &self.args.force_camera_config));
             log_error!(self.state.set_playback_state(PlaybackState::Playing as i32));
         }
 
         if let Some(seek) = &self.args.seek {
-            log_error!(self.state.seek(seek.to_owned()));
+            log_error!(self.state.seek(seek.to_owned()).await);
         }
 
         if let Some(count) = &self.args.entity_count {
@@ -79,6 +95,15 @@ impl Core {
             }
         }
 
+        self.state.save_embeddings = self.args.save_embeddings;
+
+        if self.args.auto_entities {
+            self.state.entity_lifecycle = Some(EntityLifecycle::new(
+                self.args.entity_birth_frames,
+                self.args.entity_death_frames,
+            ));
+        }
+
         if let Some(realtime) = &self.args.realtime {
             self.state.experiment.realtime_mode = *realtime;
         }
@@ -107,9 +132,141 @@ impl Core {
             "count.confidence_features_removed",
             "Number of animal features not passing confidence threshold"
         );
+        describe_counter!(
+            "count.gated_assignments",
+            "Number of assignments rejected by the gating radius"
+        );
+        describe_counter!("count.lost_entities", "Number of lost entities");
+        describe_counter!(
+            "count.ambiguous_frames",
+            "Number of frames with an ambiguous assignment of ids"
+        );
+
+        if self.args.batch_mode() {
+            self.start_batch().await?;
+        }
         Ok(())
     }
 
+    async fn start_batch(&mut self) -> Result<()> {
+        // Every frame has to be tracked, so we wait until all components are connected.
+        while self.state.connections.has_pending_connections() {
+            self.state.connections.update_connections().await;
+        }
+        self.state.experiment.realtime_mode = false;
+        self.state.experiment_changed = true;
+        let items = match &self.args.batch_queue {
+            Some(queue) => read_queue(queue)?,
+            None => vec![],
+        };
+        let mut batch = BatchRun::new(items, self.state.experiment.arena.clone());
+        if let Some(video) = &self.args.video {
+            // A single video is already opened during initialization.
+            if self.state.video_decoder.is_none() {
+                return Err(anyhow::anyhow!("Batch mode requires an open video"));
+            }
+            let item = BatchItem {
+                video: video.clone(),
+                output: self
+                    .args
+                    .output
+                    .clone()
+                    .context("Missing batch output path")?,
+                sidecar: None,
+            };
+            match self.check_batch_components() {
+                Ok(_) => {
+                    self.state
+                        .set_playback_state(PlaybackState::Playing as i32)?;
+                    self.state.start_recording()?;
+                    batch.start(item, self.frame_count());
+                }
+                Err(e) => {
+                    batch.failed(&item, &e);
+                    self.state.close_decoder();
+                }
+            }
+        }
+        self.batch = Some(batch);
+        if self.args.batch_queue.is_some() && !self.start_next_batch_video().await {
+            return Err(anyhow::anyhow!("Batch queue does not contain any video"));
+        }
+        Ok(())
+    }
+
+    /// Open the next video of the batch queue, skipping videos which fail to open. Returns false,
+    /// if the queue is exhausted.
+    async fn start_next_batch_video(&mut self) -> bool {
+        loop {
+            let item = match self.batch.as_mut().and_then(|batch| batch.next_item()) {
+                Some(item) => item,
+                None => return false,
+            };
+            match self.open_batch_video(&item).await {
+                Ok(_) => {
+                    let frame_count = self.frame_count();
+                    self.batch.as_mut().unwrap().start(item, frame_count);
+                    return true;
+                }
+                Err(e) => self.batch.as_mut().unwrap().failed(&item, &e),
+            }
+        }
+    }
+
+    /// Every frame of a batch video has to be tracked, which is impossible without a feature
+    /// detector and a matcher.
+    fn check_batch_components(&self) -> Result<()> {
+        if self.state.connections.feature_detector().is_none() {
+            return Err(anyhow::anyhow!("No feature detector connected"));
+        }
+        if self.state.connections.matcher().is_none() {
+            return Err(anyhow::anyhow!("No matcher connected"));
+        }
+        Ok(())
+    }
+
+    async fn open_batch_video(&mut self, item: &BatchItem) -> Result<()> {
+        self.check_batch_components()?;
+        let overrides = item.overrides()?;
+        self.state.experiment.arena = match &overrides.arena {
+            Some(arena) => Some(arena.clone()),
+            None => self.batch.as_ref().and_then(|b| b.default_arena.clone()),
+        };
+        self.state.experiment_changed = true;
+        let camera_config = match &overrides.camera_config {
+            Some(id) => Some(id.clone()),
+            None => self.args.force_camera_config.clone(),
+        };
+        self.state.open_video(item.video.clone(), &camera_config)?;
+        log_error!(self.state.reset_matcher(None).await);
+        self.state
+            .set_playback_state(PlaybackState::Playing as i32)?;
+        self.state.start_recording()?;
+        Ok(())
+    }
+
+    /// Save the track of the current batch video.
+    fn finish_batch_video(&mut self) {
+        let item = match self.batch.as_mut().and_then(|batch| batch.finish()) {
+            Some(item) => item,
+            None => return,
+        };
+        let result = self
+            .state
+            .save_track(&item.output.to_string_lossy())
+            .with_context(|| format!("Failed to save track to {}", item.output.display()));
+        if let Err(e) = result {
+            self.batch.as_mut().unwrap().failed(&item, &e);
+        }
+    }
+
+    fn frame_count(&self) -> u32 {
+        match &self.state.experiment.video_info {
+            Some(info) => info.frame_count,
+            None => 0,
+        }
+    }
+
     pub async fn finish(&mut self, tasks: &[&Option<JoinHandle<()>>]) -> Result<Empty> {
         if self.state.experiment.recording_state == RecordingState::Recording as i32 {
             self.finish_recording().await?;
@@ -133,43 +290,63 @@ impl Core {
         }
 
         let mut fps = self.state.experiment.target_fps;
-        let mut fps_interval =
-            tokio::time::interval(std::time::Duration::from_secs_f64(1.0 / fps as f64));
+        let mut playback_clock = PlaybackClock::new(fps);
 
         let mut decoder_task: Option<tokio::task::JoinHandle<()>> = None;
-        let mut tracking_task: Option<tokio::task::JoinHandle<()>> = None;
+        let mut tracking = TrackingPipeline::new(self.state.tracking_pipeline_depth);
         let mut observer_task: Option<tokio::task::JoinHandle<()>> = None;
         let mut encoder_task = None;
+        let mut supervisor_interval = tokio::time::interval(std::time::Duration::from_secs(1));
         let mut last_frame_start = std::time::Instant::now();
         let (decoder_tx, mut decoder_rx) = channel(16);
         let (tracking_tx, mut tracking_rx) = channel(16);
 
+        if self.batch.is_some() {
+            self.start_decoder_task(&mut decoder_task, &decoder_tx);
+        }
+
         loop {
+            let component_status = self.state.connections.status();
+            if component_status != self.state.experiment.component_status {
+                self.state.experiment.component_status = component_status;
+                self.state.experiment_changed = true;
+            }
+            let experiment_changed = std::mem::take(&mut self.state.experiment_changed);
+            self.publisher
+                .publish(&self.state.experiment, experiment_changed);
             if fps != self.state.experiment.target_fps {
                 fps = self.state.experiment.target_fps;
-                fps_interval =
-                    tokio::time::interval(std::time::Duration::from_secs_f64(1.0 / fps as f64));
+                playback_clock.set_fps(fps);
             }
-            let image_timer = fps_interval.tick();
+            let image_timer = playback_clock.tick();
 
             tokio::select! {
                 biased;
                 Some(command) = self.command_rx.recv() => {
                     let result = self.handle_command(command.request.clone()).await;
+                    self.state.experiment_changed = true;
                     match command.request {
-                        Command::Seek(_) => {
+                        Command::Seek(frame) => {
                             if let Some(task) = decoder_task.take() {
                                 task.abort();
                             }
-                            if let Some(task) = tracking_task.take() {
-                                task.abort();
-                            }
+                            tracking.abort();
+                            // Discard results of aborted tasks, which were already sent
+                            while tracking_rx.try_recv().is_ok() {}
+                            log_error!(self.state.reset_matcher(Some(frame)).await);
+                            playback_clock.reset();
                             self.start_decoder_task(&mut decoder_task, &decoder_tx);
                             self.state.experiment.last_features = None;
                         },
+                        Command::OpenVideo(_) if result.is_ok() => {
+                            // Frames of the previous video must not reach the matcher anymore
+                            tracking.abort();
+                            while tracking_rx.try_recv().is_ok() {}
+                            log_error!(self.state.reset_matcher(None).await);
+                        },
                         Command::Shutdown(_) => {
-                            self.finish(&[&decoder_task, &tracking_task, &encoder_task])
-                                .await?;
+                            tracking.abort();
+                            self.finish(&[&decoder_task, &encoder_task]).await?;
                             command.result_tx.send(Ok(Empty {})).unwrap();
                             break;
                         },
@@ -177,9 +354,10 @@ impl Core {
                     }
                     command.result_tx.send(result).unwrap();
                 }
-                _ = image_timer => {
+                _ = image_timer, if self.batch.is_none() => {
+                    playback_clock.advance();
                     if self.state.experiment.playback_state == PlaybackState::Playing as i32 &&
-                        (self.state.experiment.realtime_mode || tracking_task.is_none()) {
+                        (self.state.experiment.realtime_mode || !tracking.is_full()) {
                         if decoder_task.is_some() {
                             metrics::increment_counter!("count.playback_dropped_frames");
                         } else {
@@ -189,6 +367,7 @@ impl Core {
                         }
                     } else if self.state.experiment.playback_state == PlaybackState::Paused as i32 {
                             last_frame_start = std::time::Instant::now();
+                            playback_clock.reset();
                     }
                 }
                 Some(image_request) = self.image_rx.recv() => {
@@ -199,51 +378,163 @@ impl Core {
                     decoder_task = None;
                     match image_result {
                         Ok(image) => {
-                            self.state.handle_image_result(image.clone());
-                            if tracking_task.is_none() {
-                                start_tracking_task(
-                                    &self.state,
-                                    &mut tracking_task,
-                                    &tracking_tx,
+                            if self.batch.is_none() {
+                                self.schedule_next_frame(
+                                    &mut playback_clock,
+                                    last_frame_start,
                                     &image);
                             }
+                            self.state.handle_image_result(image.clone());
+                            let tracking_started = start_tracking_task(
+                                &self.state,
+                                &mut tracking,
+                                &tracking_tx,
+                                &image);
                             self.start_encoder_task(&mut encoder_task, &image).await;
+                            if let Some(batch) = &mut self.batch {
+                                if !tracking_started {
+                                    batch.frame_dropped();
+                                }
+                            }
                         }
                         Err(e) => {
                             log::error!("Error while decoding image: {}", e);
+                            if let Some(batch) = &mut self.batch {
+                                batch.decoding_failed();
+                            }
                             self.state.close_decoder();
                         }
                     }
+                    if self.advance_batch(&tracking, &mut decoder_task, &decoder_tx).await {
+                        self.finish(&[&decoder_task, &encoder_task]).await?;
+                        break;
+                    }
                 }
                 Some(tracking_result) = tracking_rx.recv() => {
-                    tracking_task = None;
+                    tracking.task_finished();
                     match tracking_result {
                         Ok(result) => {
-                            let frame_number = result.frame_number;
                             self.state.handle_tracking_result(result);
+                            // Track the latest image, if it was skipped while the pipeline was full
                             if let Some(image) =  &self.state.experiment.last_image {
-                                if image.frame_number != frame_number {
+                                if tracking.last_frame_number() != Some(image.frame_number) {
                                     start_tracking_task(
                                         &self.state,
-                                        &mut tracking_task,
+                                        &mut tracking,
                                         &tracking_tx,
                                         &image);
                                 }
                             }
                             start_observer_task(&self.state, &mut observer_task);
+                            if let Some(batch) = &mut self.batch {
+                                batch.frame_tracked();
+                            }
                         }
                         Err(e) => {
                             log::warn!("Tracking failed: {}", e);
+                            if let Some(e) = e.downcast_ref::<ComponentError>() {
+                                if e.is_unavailable() {
+                                    self.state.connections.reconnect(e.service_type);
+                                }
+                            }
+                            if let Some(batch) = &mut self.batch {
+                                batch.frame_failed();
+                            }
                         }
                     }
+                    if self.advance_batch(&tracking, &mut decoder_task, &decoder_tx).await {
+                        self.finish(&[&decoder_task, &encoder_task]).await?;
+                        break;
+                    }
                 }
                 _ = self.state.connections.update_connections(),
                     if self.state.connections.has_pending_connections() => {}
+                _ = supervisor_interval.tick() => {
+                    self.state.connections.supervise().await;
+                }
                 Some(state_request) = self.state_rx.recv() => {
                     state_request.result_tx.send(self.state.experiment.clone()).unwrap();
                 }
             }
         }
+        self.finish_batch()
+    }
+
+    /// Replayed videos with timestamps are paced by their timestamps. Live sources are paced by
+    /// the source itself.
+    fn schedule_next_frame(
+        &self,
+        playback_clock: &mut PlaybackClock,
+        frame_start: std::time::Instant,
+        image: &Image,
+    ) {
+        let video_fps = match &self.state.experiment.video_info {
+            Some(info) if info.frame_count > 0 => info.fps,
+            _ => return,
+        };
+        if self.state.experiment.playback_state != PlaybackState::Playing as i32 {
+            return;
+        }
+        let speed = match video_fps > 0.0 {
+            true => self.state.experiment.target_fps as f64 / video_fps,
+            false => 1.0,
+        };
+        playback_clock.frame_decoded(
+            tokio::time::Instant::from_std(frame_start),
+            image.timestamp,
+            speed,
+        );
+    }
+
+    /// In batch mode, the next frame is decoded as soon as the tracking pipeline has room for
+    /// it. At the end of a video, its track is saved and the next video of the queue is opened.
+    /// Returns true, if the batch run is complete.
+    async fn advance_batch(
+        &mut self,
+        tracking: &TrackingPipeline,
+        decoder_task: &mut Option<JoinHandle<()>>,
+        decoder_tx: &Sender<Result<Image>>,
+    ) -> bool {
+        if self.batch.is_none() || tracking.is_full() || decoder_task.is_some() {
+            return false;
+        }
+        let end_of_stream = match &self.state.video_decoder {
+            Some(decoder) => decoder.lock().unwrap().end_of_stream(),
+            None => true,
+        };
+        if end_of_stream {
+            if !tracking.is_empty() {
+                return false;
+            }
+            self.finish_batch_video();
+            if !self.start_next_batch_video().await {
+                return true;
+            }
+        }
+        self.start_decoder_task(decoder_task, decoder_tx);
+        false
+    }
+
+    fn finish_batch(&mut self) -> Result<()> {
+        // The current video is unfinished, if the batch run was shut down early.
+        self.finish_batch_video();
+        let batch = match &self.batch {
+            Some(batch) => batch,
+            None => return Ok(()),
+        };
+        batch.print_summary();
+        if let Some(path) = self.args.report_path() {
+            batch
+                .write_report(&path)
+                .with_context(|| format!("Failed to write report to {}", path.display()))?;
+        }
+        let failed_videos = batch.failed_videos();
+        if failed_videos > 0 {
+            return Err(anyhow::anyhow!(
+                "Batch processing failed for {} videos",
+                failed_videos
+            ));
+        }
         Ok(())
     }
 
@@ -265,7 +556,7 @@ impl Core {
                 self.state.set_undistort_mode(mode)?;
             }
             Command::Seek(frame) => {
-                self.state.seek(frame)?;
+                self.state.seek(frame).await?;
             }
             Command::OpenVideo(path) => {
                 self.state
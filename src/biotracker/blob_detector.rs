@@ -0,0 +1,272 @@
+use super::{protocol::*, DoubleBuffer};
+use anyhow::Result;
+use cv::{
+    core::{Point, Ptr, Scalar, Size, Vector},
+    prelude::*,
+    video::BackgroundSubtractorMOG2,
+};
+pub use feature_detector_server::FeatureDetector;
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use tonic::{Request, Response, Status};
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum BackgroundModel {
+    /// Gaussian mixture background subtraction
+    Mog2,
+    /// Running approximation of the median of each pixel
+    Median,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct BlobDetectorConfig {
+    background: BackgroundModel,
+    /// Foreground threshold. Variance threshold for MOG2, intensity difference for median.
+    threshold: f64,
+    /// Background learning rate between 0 and 1, negative for an automatic choice
+    learning_rate: f64,
+    /// Number of frames used to build the MOG2 background model
+    history: i32,
+    /// Blobs smaller or larger than this, in pixels, are ignored
+    min_area: f64,
+    max_area: f64,
+    /// Kernel size of the morphological opening and closing of the foreground mask
+    morphology_size: i32,
+}
+
+impl Default for BlobDetectorConfig {
+    fn default() -> Self {
+        Self {
+            background: BackgroundModel::Mog2,
+            threshold: 25.0,
+            learning_rate: -1.0,
+            history: 500,
+            min_area: 20.0,
+            max_area: f64::INFINITY,
+            morphology_size: 5,
+        }
+    }
+}
+
+enum Background {
+    Mog2(Ptr<BackgroundSubtractorMOG2>),
+    Median(Option<Mat>),
+}
+
+struct BlobDetectorState {
+    config: BlobDetectorConfig,
+    background: Option<Background>,
+    image_buffers: DoubleBuffer,
+}
+
+/// Built-in feature detector for animals which contrast well with a static background. Foreground
+/// blobs are detected as animals with a head and a center node.
+pub struct BlobDetectorService {
+    inner: Arc<Mutex<BlobDetectorState>>,
+}
+
+impl Default for BlobDetectorService {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(BlobDetectorState {
+                config: BlobDetectorConfig::default(),
+                background: None,
+                image_buffers: DoubleBuffer::new(),
+            })),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl FeatureDetector for BlobDetectorService {
+    async fn detect_features(
+        &self,
+        request: Request<Image>,
+    ) -> Result<Response<DetectorResponse>, Status> {
+        let image = request.into_inner();
+        let inner = self.inner.clone();
+        // Image processing would block the runtime of the core
+        let features = tokio::task::spawn_blocking(move || inner.lock().unwrap().detect(&image))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(|e| Status::internal(format!("Blob detection failed: {}", e)))?;
+        Ok(Response::new(DetectorResponse {
+            features: Some(features),
+            skeleton: Some(skeleton()),
+        }))
+    }
+
+    async fn set_config(
+        &self,
+        request: Request<ComponentConfig>,
+    ) -> Result<Response<Empty>, Status> {
+        let config = request.into_inner().config_json;
+        let config: BlobDetectorConfig = serde_json::from_str(&config).map_err(|e| {
+            Status::invalid_argument(format!("Could not parse config: {}", e.to_string()))
+        })?;
+        let mut inner = self.inner.lock().unwrap();
+        inner.config = config;
+        // The background model is rebuilt with the new parameters
+        inner.background = None;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn heartbeat(&self, _: Request<Empty>) -> Result<Response<Empty>, Status> {
+        Ok(Response::new(Empty {}))
+    }
+}
+
+pub fn skeleton() -> SkeletonDescriptor {
+    SkeletonDescriptor {
+        id: 0,
+        edges: vec![SkeletonEdge {
+            source: 0,
+            target: 1,
+        }],
+        node_names: vec!["head".to_owned(), "center".to_owned()],
+        front_index: 0,
+        center_index: 1,
+    }
+}
+
+impl BlobDetectorState {
+    fn detect(&mut self, image: &Image) -> Result<Features> {
+        let mask = self.foreground_mask(image)?;
+        let mut contours = Vector::<Vector<Point>>::new();
+        cv::imgproc::find_contours(
+            &mask,
+            &mut contours,
+            cv::imgproc::RETR_EXTERNAL,
+            cv::imgproc::CHAIN_APPROX_SIMPLE,
+            Point::default(),
+        )?;
+        let mut features = vec![];
+        for contour in contours.iter() {
+            let area = cv::imgproc::contour_area(&contour, false)?;
+            if area < self.config.min_area || area > self.config.max_area {
+                continue;
+            }
+            if let Some(feature) = blob_feature(&contour)? {
+                features.push(feature);
+            }
+        }
+        Ok(Features {
+            features,
+            frame_number: image.frame_number,
+            ..Default::default()
+        })
+    }
+
+    fn foreground_mask(&mut self, image: &Image) -> Result<Mat> {
+        let config = self.config.clone();
+        let frame = &self.image_buffers.get(image)?.mat;
+        if self.background.is_none() {
+            self.background = Some(match config.background {
+                BackgroundModel::Mog2 => {
+                    Background::Mog2(cv::video::create_background_subtractor_mog2(
+                        config.history,
+                        config.threshold,
+                        false,
+                    )?)
+                }
+                BackgroundModel::Median => Background::Median(None),
+            });
+        }
+        let background = self.background.as_mut().unwrap();
+        let mut mask = Mat::default();
+        match background {
+            Background::Mog2(subtractor) => {
+                subtractor.apply(frame, &mut mask, config.learning_rate)?;
+            }
+            Background::Median(median) => {
+                let mut gray = Mat::default();
+                cv::imgproc::cvt_color(frame, &mut gray, cv::imgproc::COLOR_BGR2GRAY, 0)?;
+                if median.is_none() {
+                    *median = Some(gray.try_clone()?);
+                }
+                let median = median.as_mut().unwrap();
+                let mut difference = Mat::default();
+                cv::core::absdiff(&gray, &*median, &mut difference)?;
+                cv::imgproc::threshold(
+                    &difference,
+                    &mut mask,
+                    config.threshold,
+                    255.0,
+                    cv::imgproc::THRESH_BINARY,
+                )?;
+                // Moving each pixel towards the current frame by a fixed step converges to
+                // the median of its values over time.
+                let step = (config.learning_rate * 255.0).max(1.0);
+                let mut brighter = Mat::default();
+                let mut darker = Mat::default();
+                cv::core::compare(&gray, &*median, &mut brighter, cv::core::CMP_GT)?;
+                cv::core::compare(&gray, &*median, &mut darker, cv::core::CMP_LT)?;
+                for (changed, step) in [(brighter, step), (darker, -step)] {
+                    let mut updated = Mat::default();
+                    cv::core::add(&*median, &Scalar::all(step), &mut updated, &changed, -1)?;
+                    updated.copy_to_masked(median, &changed)?;
+                }
+            }
+        }
+
+        let size = config.morphology_size;
+        if size > 1 {
+            let kernel = cv::imgproc::get_structuring_element(
+                cv::imgproc::MORPH_ELLIPSE,
+                Size::new(size, size),
+                Point::new(-1, -1),
+            )?;
+            for operation in [cv::imgproc::MORPH_OPEN, cv::imgproc::MORPH_CLOSE] {
+                let mut result = Mat::default();
+                cv::imgproc::morphology_ex(
+                    &mask,
+                    &mut result,
+                    operation,
+                    &kernel,
+                    Point::new(-1, -1),
+                    1,
+                    cv::core::BORDER_CONSTANT,
+                    cv::imgproc::morphology_default_border_value()?,
+                )?;
+                mask = result;
+            }
+        }
+        Ok(mask)
+    }
+}
+
+/// Feature with head and center node of a blob. The head lies on the principal axis of the blob.
+/// Its end closer to the centroid is taken as head, because most animals carry their mass in
+/// front.
+fn blob_feature(contour: &Vector<Point>) -> Result<Option<Feature>> {
+    let moments = cv::imgproc::moments(contour, false)?;
+    if moments.m00 <= 0.0 {
+        return Ok(None);
+    }
+    let center_x = moments.m10 / moments.m00;
+    let center_y = moments.m01 / moments.m00;
+    let orientation = 0.5 * (2.0 * moments.mu11).atan2(moments.mu20 - moments.mu02);
+    let (axis_x, axis_y) = (orientation.cos(), orientation.sin());
+    let (mut min, mut max) = (0.0f64, 0.0f64);
+    for point in contour.iter() {
+        let t = (point.x as f64 - center_x) * axis_x + (point.y as f64 - center_y) * axis_y;
+        min = min.min(t);
+        max = max.max(t);
+    }
+    let head = if -min < max { min } else { max };
+    let node = |x: f64, y: f64| SkeletonNode {
+        x: x as f32,
+        y: y as f32,
+        score: 1.0,
+    };
+    Ok(Some(Feature {
+        image_nodes: vec![
+            node(center_x + head * axis_x, center_y + head * axis_y),
+            node(center_x, center_y),
+        ],
+        score: 1.0,
+        ..Default::default()
+    }))
+}
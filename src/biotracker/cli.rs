@@ -11,6 +11,16 @@ pub struct CommandLineArguments {
     /// Start experiment with <count> entities
     #[arg(long)]
     pub entity_count: Option<u64>,
+    /// Create entities for detections which stay unmatched, and retire entities which are not
+    /// detected anymore
+    #[arg(long)]
+    pub auto_entities: bool,
+    /// Number of frames after which unmatched detections create entities
+    #[arg(long, default_value_t = 10)]
+    pub entity_birth_frames: u32,
+    /// Number of frames after which undetected entities are retired
+    #[arg(long, default_value_t = 300)]
+    pub entity_death_frames: u32,
     /// Skip frames if tracking is too slow
     #[arg(long)]
     pub realtime: Option<bool>,
@@ -23,12 +33,22 @@ pub struct CommandLineArguments {
     /// Seek to frame
     #[arg(long)]
     pub seek: Option<u32>,
+    /// Fail, if seeking or playing a video does not reach the frame expected from its frame index
+    #[arg(long)]
+    pub verify_seeks: bool,
     /// Number of OpenCV worker threads
     #[arg(long, default_value_t = 4)]
     pub cv_worker_threads: u32,
+    /// Number of frames which are tracked concurrently. Feature detection runs in parallel, while
+    /// matching stays in frame order.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub tracking_pipeline_depth: u32,
     /// Path to robofish track file
     #[arg(long)]
     pub track: Option<std::path::PathBuf>,
+    /// Keep the appearance embeddings of features in saved tracks
+    #[arg(long)]
+    pub save_embeddings: bool,
     /// Force loading of camera settings, this makes it possible to apply undistortion to videos.
     #[arg(long)]
     pub force_camera_config: Option<String>,
@@ -38,19 +58,70 @@ pub struct CommandLineArguments {
     /// Run biotracker in headless mode, without GUI
     #[arg(long)]
     pub headless: bool,
+    /// Track every frame of <video> as fast as possible, save the track to <output> and exit
+    #[arg(long, requires_all = ["video", "output"])]
+    pub batch: bool,
+    /// Path of the track file written in batch mode
+    #[arg(long)]
+    pub output: Option<std::path::PathBuf>,
+    /// Batch mode for a directory of videos, or a text file listing one video per line. Tracks are
+    /// saved next to each video.
+    #[arg(long, conflicts_with_all = ["video", "batch"])]
+    pub batch_queue: Option<std::path::PathBuf>,
+    /// Path of the summary report written at the end of a batch run or rematch
+    #[arg(long)]
+    pub report: Option<std::path::PathBuf>,
+    /// Re-match the identities of a recorded track over all frames at once, save the corrected
+    /// track to <output> and exit
+    #[arg(long, requires = "output", conflicts_with_all = ["video", "batch", "batch_queue"])]
+    pub rematch: Option<std::path::PathBuf>,
+    /// Maximum number of frames, which an entity may be missing in a rematched track
+    #[arg(long, default_value_t = 25)]
+    pub rematch_max_gap: u32,
+    /// Maximum speed of entities in a rematched track, in pixels per frame
+    #[arg(long, default_value_t = 20.0)]
+    pub rematch_max_speed: f64,
 }
 
 impl CommandLineArguments {
     pub fn canonicalize_paths(mut self) -> Result<Self> {
         canonicalize_path(&mut self.config)?;
-        for arg in [&mut self.track].iter_mut() {
+        for arg in [&mut self.track, &mut self.batch_queue, &mut self.rematch].iter_mut() {
             match arg {
                 Some(path) => canonicalize_path(path)?,
                 None => {}
             }
         }
+        // Output files do not exist yet, so they can not be canonicalized. They are still made
+        // absolute, because the working directory changes to the config directory at startup.
+        for arg in [&mut self.output, &mut self.report].iter_mut() {
+            match arg {
+                Some(path) if path.is_relative() => *path = std::env::current_dir()?.join(&path),
+                _ => {}
+            }
+        }
         Ok(self)
     }
+
+    pub fn batch_mode(&self) -> bool {
+        self.batch || self.batch_queue.is_some()
+    }
+
+    /// Path of the batch or rematch report. Batch queues and rematches write a report by default.
+    pub fn report_path(&self) -> Option<std::path::PathBuf> {
+        if self.report.is_some() {
+            return self.report.clone();
+        }
+        if self.rematch.is_some() {
+            return Some(self.output.as_ref()?.with_extension("rematch.json"));
+        }
+        let queue = self.batch_queue.as_ref()?;
+        let directory = match queue.is_dir() {
+            true => queue.as_path(),
+            false => queue.parent()?,
+        };
+        Some(directory.join("batch_report.json"))
+    }
 }
 
 fn canonicalize_path(path: &mut std::path::PathBuf) -> Result<()> {
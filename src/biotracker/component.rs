@@ -1,18 +1,63 @@
 use super::observer_client::ObserverClient;
 use super::port::PortFinder;
-use super::{matcher::MatcherService, protocol::*, python_process::PythonProcess, ComponentConfig};
+use super::{
+    blob_detector::BlobDetectorService, matcher::MatcherService,
+    onnx_detector::OnnxDetectorService, process::ComponentProcess, protocol::*,
+    sort_matcher::SortMatcherService, ComponentConfig,
+};
 use anyhow::Result;
+use feature_detector_server::FeatureDetectorServer;
+use futures::future::select_all;
 use matcher_server::MatcherServer;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::task::JoinHandle;
 use tonic::transport::Channel as ClientChannel;
 use tonic::transport::Server;
 
+/// Number of missed heartbeats, after which a component is considered failed
+const MAX_MISSED_HEARTBEATS: u32 = 3;
+/// Restarts are delayed exponentially, up to this limit
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+/// Components which are ready for this long are considered stable, their backoff is reset
+const STABLE_DURATION: Duration = Duration::from_secs(60);
+
 #[derive(Default)]
 pub struct ComponentConnections {
-    processes: Vec<PythonProcess>,
+    components: Vec<SupervisedComponent>,
     connections: Vec<ComponentConnection>,
-    pending_connections: Vec<JoinHandle<Result<ComponentConnection>>>,
+    pending_connections: Vec<(String, JoinHandle<Result<ComponentConnection>>)>,
+}
+
+/// Supervision state of a configured component
+struct SupervisedComponent {
+    config: ComponentConfig,
+    service_type: ServiceType,
+    // Address used by the component to serve, and by the core to connect
+    serve_address: String,
+    connect_address: String,
+    process: Option<ComponentProcess>,
+    status: ComponentStatus,
+    heartbeat_task: Option<JoinHandle<bool>>,
+    missed_heartbeats: u32,
+    consecutive_failures: u32,
+    ready_since: Option<Instant>,
+    restart_at: Option<Instant>,
+    /// Stops the failed process and starts a new one. Stopping may take the whole grace period,
+    /// so it runs in the background.
+    restart_task: Option<JoinHandle<Result<Option<ComponentProcess>>>>,
+}
+
+impl SupervisedComponent {
+    /// Components with an address are running remotely and can not be restarted by us.
+    fn is_remote(&self) -> bool {
+        self.config.address.is_some()
+    }
+
+    fn set_state(&mut self, state: ComponentState, message: &str) {
+        self.status.state = state as i32;
+        self.status.message = message.to_owned();
+    }
 }
 
 impl ComponentConnections {
@@ -23,50 +68,266 @@ impl ComponentConnections {
     ) -> Result<()> {
         let mut port_finder = PortFinder::new(port_range_start);
         for config in configs {
-            let port = port_finder.next()?;
-            let address = format!("127.0.0.1:{}", port);
-            self.start_component(config.clone(), address).await?;
-            let service = ServiceType::from_str_name(&config.services[0]).unwrap();
-            let task =
-                tokio::spawn(async move { ComponentConnection::new(service, &config, port).await });
-            self.pending_connections.push(task);
+            let service_type = ServiceType::from_str_name(&config.services[0]).unwrap();
+            let (serve_address, connect_address) = match &config.address {
+                Some(address) => (address.clone(), address.clone()),
+                None => {
+                    let port = port_finder.next()?;
+                    (
+                        format!("127.0.0.1:{}", port),
+                        format!("http://127.0.0.1:{}", port),
+                    )
+                }
+            };
+            let mut component = SupervisedComponent {
+                status: ComponentStatus {
+                    id: config.id.clone(),
+                    state: ComponentState::Starting as i32,
+                    ..Default::default()
+                },
+                config,
+                service_type,
+                serve_address,
+                connect_address,
+                process: None,
+                heartbeat_task: None,
+                missed_heartbeats: 0,
+                consecutive_failures: 0,
+                ready_since: None,
+                restart_at: None,
+                restart_task: None,
+            };
+            if !component.is_remote() {
+                component.process = start_component(&component.config, &component.serve_address)?;
+            }
+            self.pending_connections
+                .push((component.config.id.clone(), spawn_connect(&component)));
+            self.components.push(component);
         }
         Ok(())
     }
 
     pub async fn stop_components(&mut self) -> Result<()> {
-        for mut process in self.processes.drain(..) {
-            process.stop().await;
+        for component in &mut self.components {
+            if let Some(task) = component.heartbeat_task.take() {
+                task.abort();
+            }
+            if let Some(mut process) = component.process.take() {
+                process.stop().await;
+            }
+            if let Some(task) = component.restart_task.take() {
+                if let Ok(Ok(Some(mut process))) = task.await {
+                    process.stop().await;
+                }
+            }
         }
         self.connections.clear();
-        self.pending_connections.clear();
+        for (_, task) in self.pending_connections.drain(..) {
+            task.abort();
+        }
         Ok(())
     }
 
+    /// Status of all components, as reported in the experiment
+    pub fn status(&self) -> Vec<ComponentStatus> {
+        self.components.iter().map(|c| c.status.clone()).collect()
+    }
+
+    /// Mark a component which became unavailable as failed. It is restarted, or reconnected for
+    /// remote components, by the supervisor.
+    pub fn reconnect(&mut self, service_type: ServiceType) {
+        let id = match self
+            .connections
+            .iter()
+            .find(|c| c.service_type == service_type)
+        {
+            Some(connection) => connection.id.clone(),
+            None => return,
+        };
+        self.component_failed(&id, "Component unavailable");
+    }
+
+    fn component_failed(&mut self, id: &str, message: &str) {
+        self.connections.retain(|c| c.id != id);
+        self.pending_connections.retain(|(pending_id, task)| {
+            if pending_id == id {
+                task.abort();
+            }
+            pending_id != id
+        });
+        let component = match self.components.iter_mut().find(|c| c.config.id == id) {
+            Some(component) => component,
+            None => return,
+        };
+        if let Some(task) = component.heartbeat_task.take() {
+            task.abort();
+        }
+        if let Some(ready_since) = component.ready_since.take() {
+            if ready_since.elapsed() > STABLE_DURATION {
+                component.consecutive_failures = 0;
+            }
+        }
+        let backoff = Duration::from_secs(1 << component.consecutive_failures.min(6));
+        let backoff = backoff.min(MAX_RESTART_BACKOFF);
+        component.consecutive_failures += 1;
+        component.missed_heartbeats = 0;
+        component.restart_at = Some(Instant::now() + backoff);
+        component.set_state(ComponentState::Failed, message);
+        log::error!(
+            "{}: {}, restarting in {}s",
+            component.config.id,
+            message,
+            backoff.as_secs()
+        );
+    }
+
+    /// Called periodically by the core. Detects crashed processes and unresponsive components,
+    /// and restarts them when their backoff expired.
+    pub async fn supervise(&mut self) {
+        let mut failed = vec![];
+        for component in &mut self.components {
+            let task = match component.restart_task.take() {
+                Some(task) if task.is_finished() => task,
+                task => {
+                    component.restart_task = task;
+                    continue;
+                }
+            };
+            let id = component.config.id.clone();
+            let result = task
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|result| result);
+            match result {
+                Ok(process) => {
+                    component.process = process;
+                    self.pending_connections
+                        .push((id, spawn_connect(component)));
+                }
+                Err(e) => failed.push((id, format!("Failed to restart: {}", e))),
+            }
+        }
+        for component in &mut self.components {
+            let id = component.config.id.clone();
+            let state = ComponentState::from_i32(component.status.state);
+            if state == Some(ComponentState::Failed) {
+                continue;
+            }
+            if let Some(process) = &component.process {
+                if process.has_exited() {
+                    failed.push((id, process.exit_message()));
+                    continue;
+                }
+            }
+            if state != Some(ComponentState::Ready) {
+                continue;
+            }
+            // Heartbeats are sent in the background, the result is checked one period later.
+            match component.heartbeat_task.take() {
+                Some(task) if task.is_finished() => match task.await {
+                    Ok(true) => component.missed_heartbeats = 0,
+                    _ => component.missed_heartbeats += 1,
+                },
+                Some(task) => {
+                    task.abort();
+                    component.missed_heartbeats += 1;
+                }
+                None => {}
+            }
+            if component.missed_heartbeats >= MAX_MISSED_HEARTBEATS {
+                failed.push((id, "Component not responding".to_owned()));
+                continue;
+            }
+            if let Some(connection) = self.connections.iter().find(|c| c.id == id) {
+                let mut client = connection.client.clone();
+                component.heartbeat_task = Some(tokio::spawn(async move {
+                    let timeout = Duration::from_secs(1);
+                    match tokio::time::timeout(timeout, client.heartbeat()).await {
+                        Ok(alive) => alive,
+                        Err(_) => false,
+                    }
+                }));
+            }
+        }
+        for (id, message) in failed {
+            self.component_failed(&id, &message);
+        }
+
+        for i in 0..self.components.len() {
+            let due = match self.components[i].restart_at {
+                Some(restart_at) => restart_at <= Instant::now(),
+                None => false,
+            };
+            if !due {
+                continue;
+            }
+            let component = &mut self.components[i];
+            component.restart_at = None;
+            component.status.restarts += 1;
+            component.set_state(ComponentState::Restarting, "");
+            log::warn!("{}: restarting", component.config.id);
+            if component.is_remote() {
+                self.pending_connections
+                    .push((component.config.id.clone(), spawn_connect(component)));
+                continue;
+            }
+            // The component is connected, once the new process was started
+            let process = component.process.take();
+            let config = component.config.clone();
+            let address = component.serve_address.clone();
+            component.restart_task = Some(tokio::spawn(async move {
+                if let Some(mut process) = process {
+                    process.stop().await;
+                }
+                match config.process_config() {
+                    Some(_) => start_component(&config, &address),
+                    // Built-in components run within the core, they are only reconnected
+                    None => Ok(None),
+                }
+            }));
+        }
+    }
+
     pub fn has_pending_connections(&self) -> bool {
         !self.pending_connections.is_empty()
     }
 
     pub async fn update_connections(&mut self) -> Option<ServiceType> {
-        if let Some(task) = self.pending_connections.last_mut() {
-            match task.await.unwrap() {
-                Ok(connection) => {
-                    let service_type = connection.service_type;
-                    self.connections.push(connection);
-                    self.pending_connections.pop();
-                    return Some(service_type);
-                }
-                Err(err) => {
-                    self.pending_connections.pop();
-                    log::error!("Failed to connect to component: {}", err);
-                    return None;
+        if self.pending_connections.is_empty() {
+            return None;
+        }
+        // Reconnections may be pending for a long time, so we wait for whichever task finishes
+        // first.
+        let (result, index, _) =
+            select_all(self.pending_connections.iter_mut().map(|(_, task)| task)).await;
+        let (id, _) = self.pending_connections.remove(index);
+        match result.unwrap() {
+            Ok(connection) => {
+                let service_type = connection.service_type;
+                if let Some(component) = self.components.iter_mut().find(|c| c.config.id == id) {
+                    component.set_state(ComponentState::Ready, "");
+                    component.ready_since = Some(Instant::now());
                 }
+                self.connections.push(connection);
+                Some(service_type)
+            }
+            Err(err) => {
+                log::error!("Failed to connect to component {}: {}", id, err);
+                self.component_failed(&id, &format!("Failed to connect: {}", err));
+                None
             }
         }
-        None
     }
 
     pub async fn set_config(&mut self, config: ComponentConfig) -> Result<()> {
+        // Keep the configuration, so that it is sent again after restarts
+        if let Some(component) = self
+            .components
+            .iter_mut()
+            .find(|c| c.config.id == config.id)
+        {
+            component.config = config.clone();
+        }
         for connection in &mut self.connections {
             if connection.id == config.id {
                 connection.set_config(config).await?;
@@ -109,42 +370,142 @@ impl ComponentConnections {
         }
         clients
     }
+}
 
-    async fn start_component(&mut self, config: ComponentConfig, address: String) -> Result<()> {
-        if let Some(python_config) = &config.python_config {
-            let process = PythonProcess::start(&config, python_config, address)?;
-            self.processes.push(process);
-        } else {
-            match config.id.as_str() {
-                "HungarianMatcher" => {
-                    tokio::spawn(async move {
-                        let matcher_service = Arc::new(MatcherService::default());
-                        let matcher_server = MatcherServer::from_arc(matcher_service.clone());
-                        match Server::builder()
-                            .add_service(matcher_server)
-                            .serve(address.parse().expect("Invalid address"))
-                            .await
-                        {
-                            Ok(_) => {}
-                            Err(e) => {
-                                log::warn!("HungarianMatcher failed: {}", e);
-                            }
-                        };
-                    });
-                }
-                _ => panic!("Unknown component {}", config.id),
-            };
-        };
-        Ok(())
+fn spawn_connect(component: &SupervisedComponent) -> JoinHandle<Result<ComponentConnection>> {
+    let service_type = component.service_type;
+    let config = component.config.clone();
+    let address = component.connect_address.clone();
+    tokio::spawn(async move { ComponentConnection::new(service_type, &config, &address).await })
+}
+
+/// Start a local component. Returns the process, if the component runs in a separate process.
+fn start_component(config: &ComponentConfig, address: &str) -> Result<Option<ComponentProcess>> {
+    if let Some(process_config) = config.process_config() {
+        let process = ComponentProcess::start(config, &process_config, address.to_owned())?;
+        return Ok(Some(process));
     }
+    match config.id.as_str() {
+        "HungarianMatcher" => {
+            let address = address.to_owned();
+            tokio::spawn(async move {
+                let matcher_service = Arc::new(MatcherService::default());
+                let matcher_server = MatcherServer::from_arc(matcher_service.clone());
+                match Server::builder()
+                    .add_service(matcher_server)
+                    .serve(address.parse().expect("Invalid address"))
+                    .await
+                {
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::warn!("HungarianMatcher failed: {}", e);
+                    }
+                };
+            });
+        }
+        "SortMatcher" => {
+            let address = address.to_owned();
+            tokio::spawn(async move {
+                let matcher_server = MatcherServer::new(SortMatcherService::default());
+                match Server::builder()
+                    .add_service(matcher_server)
+                    .serve(address.parse().expect("Invalid address"))
+                    .await
+                {
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::warn!("SortMatcher failed: {}", e);
+                    }
+                };
+            });
+        }
+        "BlobDetector" => {
+            let address = address.to_owned();
+            tokio::spawn(async move {
+                let detector_server = FeatureDetectorServer::new(BlobDetectorService::default());
+                match Server::builder()
+                    .add_service(detector_server)
+                    .serve(address.parse().expect("Invalid address"))
+                    .await
+                {
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::warn!("BlobDetector failed: {}", e);
+                    }
+                };
+            });
+        }
+        "OnnxDetector" => {
+            let address = address.to_owned();
+            tokio::spawn(async move {
+                let detector_server = FeatureDetectorServer::new(OnnxDetectorService::default());
+                match Server::builder()
+                    .add_service(detector_server)
+                    .serve(address.parse().expect("Invalid address"))
+                    .await
+                {
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::warn!("OnnxDetector failed: {}", e);
+                    }
+                };
+            });
+        }
+        _ => panic!("Unknown component {}", config.id),
+    };
+    Ok(None)
+}
+
+/// Failed request to a component. This lets the core find out which component became
+/// unavailable.
+#[derive(Debug)]
+pub struct ComponentError {
+    pub service_type: ServiceType,
+    pub status: tonic::Status,
 }
 
+impl ComponentError {
+    pub fn is_unavailable(&self) -> bool {
+        self.status.code() == tonic::Code::Unavailable
+    }
+}
+
+impl std::fmt::Display for ComponentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} request failed: {}",
+            self.service_type.as_str_name(),
+            self.status.message()
+        )
+    }
+}
+
+impl std::error::Error for ComponentError {}
+
+#[derive(Clone)]
 pub enum GrpcClient {
     Matcher(MatcherClient<ClientChannel>),
     FeatureDetector(FeatureDetectorClient<ClientChannel>),
     Observer(ObserverClient<ClientChannel>),
 }
 
+impl GrpcClient {
+    /// Returns true, if the component responded. Components which do not implement heartbeats
+    /// respond with an error, but are still alive.
+    async fn heartbeat(&mut self) -> bool {
+        let result = match self {
+            GrpcClient::Matcher(client) => client.heartbeat(Empty {}).await,
+            GrpcClient::FeatureDetector(client) => client.heartbeat(Empty {}).await,
+            GrpcClient::Observer(client) => client.heartbeat(Empty {}).await,
+        };
+        match result {
+            Ok(_) => true,
+            Err(status) => status.code() != tonic::Code::Unavailable,
+        }
+    }
+}
+
 pub struct ComponentConnection {
     service_type: ServiceType,
     client: GrpcClient,
@@ -152,9 +513,12 @@ pub struct ComponentConnection {
 }
 
 impl ComponentConnection {
-    async fn new(service_type: ServiceType, config: &ComponentConfig, port: u16) -> Result<Self> {
-        let address = format!("http://127.0.0.1:{}", port);
-        let channel = ComponentConnection::poll_connect(&address).await?;
+    async fn new(
+        service_type: ServiceType,
+        config: &ComponentConfig,
+        address: &str,
+    ) -> Result<Self> {
+        let channel = ComponentConnection::poll_connect(address).await?;
         let client = match service_type {
             ServiceType::Matcher => Ok(GrpcClient::Matcher(MatcherClient::new(channel))),
             ServiceType::FeatureDetector => Ok(GrpcClient::FeatureDetector(
@@ -1,11 +1,14 @@
 use super::{
-    protocol::{CameraConfig, Image, VideoInfo},
+    frame_index::FrameIndex,
+    protocol::{Arena, CameraConfig, Image, Track, VideoInfo},
+    simulator::{Simulator, SimulatorConfig},
     undistort::UndistortMap,
     DoubleBuffer,
 };
-use anyhow::Result;
+use anyhow::{Context, Result};
 use cv::prelude::*;
 use cv::videoio::VideoCapture;
+use tokio::sync::oneshot;
 
 struct Playback {
     frame_number: u32,
@@ -30,9 +33,30 @@ struct PylonCamera<'a> {
     _pylon_raii: std::pin::Pin<Box<pylon_cxx::Pylon>>,
 }
 
-struct FakeDecoder {}
+/// Compressed video with a frame index, which seeks to exact frames.
+struct IndexedVideo {
+    capture: VideoCapture,
+    /// Seeks are inexact, until the index is ready
+    index: Option<FrameIndex>,
+    /// Index being built in the background
+    pending_index: Option<oneshot::Receiver<Result<FrameIndex>>>,
+    /// Fail, if a decoded frame is not the one expected from the index
+    verify: bool,
+    /// Frame number of the next image
+    position: u32,
+    /// The next image was already grabbed while seeking
+    grabbed: bool,
+}
+
+/// Numbered image files, e.g. from high-speed cameras.
+struct ImageSequence {
+    paths: Vec<std::path::PathBuf>,
+    /// Timestamp of each image from `timestamps.csv`, if present
+    timestamps: Vec<Option<f64>>,
+    position: usize,
+}
 
-trait VideoSampler {
+pub(super) trait VideoSampler {
     fn get_image(&mut self, mat: &mut Mat) -> Result<()>;
     fn set_exposure(&mut self, _exposure: f64) -> Result<()> {
         Err(anyhow::anyhow!("Setting Exposure not supported"))
@@ -40,16 +64,40 @@ trait VideoSampler {
     fn seek(&mut self, _target_framenumber: u32) -> Result<()> {
         Err(anyhow::anyhow!("Seek not supported"))
     }
+    /// Capture time of the last image in seconds, if the source provides it.
+    fn timestamp(&self) -> Result<Option<f64>> {
+        Ok(None)
+    }
+    /// Known positions of all entities from `track_start` on, for simulated videos.
+    fn ground_truth(&self, _track_start: u32) -> Result<Option<Track>> {
+        Ok(None)
+    }
+    /// Exact frame count, if the source knows it better than the container metadata.
+    fn frame_count(&self) -> Option<u32> {
+        None
+    }
+    /// Exact frame number of the last image, if the source knows it better than the count of
+    /// decoded frames since the last seek.
+    fn frame_number(&self) -> Option<u32> {
+        None
+    }
 }
 
 impl Playback {
-    fn open(uri: String, fps: f64) -> Result<(Playback, VideoInfo)> {
+    fn open(
+        uri: String,
+        fps: f64,
+        arena: &Option<Arena>,
+        verify_seeks: bool,
+    ) -> Result<(Playback, VideoInfo)> {
         if uri.starts_with("pylon:///") {
             Playback::open_basler(uri, fps)
         } else if uri.starts_with("fake:///") {
-            Playback::open_fake(uri, fps)
+            Playback::open_fake(uri, fps, arena)
+        } else if uri.starts_with("images://") {
+            Playback::open_images(uri, fps)
         } else {
-            Playback::open_cv(uri)
+            Playback::open_cv(uri, verify_seeks)
         }
     }
 
@@ -62,23 +110,89 @@ impl Playback {
         }
     }
 
-    fn open_fake(video_path: String, fps: f64) -> Result<(Playback, VideoInfo)> {
+    /// Opens a simulation, e.g. `fake:///?animals=8&motion=schooling`. Simulated animals are
+    /// placed in the arena, so that their world coordinates match the tracked ones.
+    fn open_fake(
+        video_path: String,
+        fps: f64,
+        arena: &Option<Arena>,
+    ) -> Result<(Playback, VideoInfo)> {
+        let simulator = Simulator::new(SimulatorConfig::from_uri(&video_path)?, arena)?;
+        let video_info = simulator.video_info(video_path, fps);
+        Ok((
+            Playback {
+                frame_number: 0,
+                sampler: Box::new(simulator),
+            },
+            video_info,
+        ))
+    }
+
+    /// Opens `images:///path/to/directory` or `images:///path/to/frame_*.tif`. Images are played
+    /// in natural order of their file names. Timestamps in `timestamps.csv` next to the images
+    /// determine the frame rate, if present.
+    fn open_images(uri: String, fps: f64) -> Result<(Playback, VideoInfo)> {
+        let pattern = std::path::Path::new(uri.strip_prefix("images://").unwrap());
+        let (directory, file_pattern) = match pattern.is_dir() {
+            true => (pattern, None),
+            false => (
+                pattern
+                    .parent()
+                    .filter(|parent| !parent.as_os_str().is_empty())
+                    .unwrap_or(std::path::Path::new(".")),
+                pattern.file_name().and_then(|name| name.to_str()),
+            ),
+        };
+        let mut paths = std::fs::read_dir(directory)
+            .with_context(|| format!("Failed to open image directory {}", directory.display()))?
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| {
+                let name = match path.file_name().and_then(|name| name.to_str()) {
+                    Some(name) => name,
+                    None => return false,
+                };
+                // Wildcards may also match other files, e.g. `timestamps.csv`
+                is_image_file(path)
+                    && file_pattern.is_none_or(|pattern| wildcard_match(pattern, name))
+            })
+            .collect::<Vec<_>>();
+        paths.sort_by_cached_key(|path| natural_key(&path.file_name().unwrap().to_string_lossy()));
+        let first_image = match paths.first() {
+            Some(path) => read_image(path)?,
+            None => return Err(anyhow::anyhow!("No images found for {}", pattern.display())),
+        };
+        let size = first_image.size()?;
+
+        let timestamps_path = directory.join("timestamps.csv");
+        let timestamps = match timestamps_path.exists() {
+            true => read_timestamps(&timestamps_path, &paths)?,
+            false => vec![None; paths.len()],
+        };
+        let fps = frame_rate(&timestamps).unwrap_or(fps);
+        let frame_count = paths.len() as u32;
         Ok((
             Playback {
                 frame_number: 0,
-                sampler: Box::new(FakeDecoder {}),
+                sampler: Box::new(ImageSequence {
+                    paths,
+                    timestamps,
+                    position: 0,
+                }),
             },
             VideoInfo {
-                path: video_path,
+                path: uri,
+                frame_count,
+                width: size.width as u32,
+                height: size.height as u32,
                 fps,
-                width: 2048,
-                height: 2048,
-                frame_count: 0,
             },
         ))
     }
 
-    fn open_cv(video_path: String) -> Result<(Playback, VideoInfo)> {
+    /// Video files are indexed in the background, so that seeking is exact once the index is
+    /// ready. Other sources, e.g. streams, and videos which cannot be indexed seek to the frame
+    /// numbers estimated by OpenCV.
+    fn open_cv(video_path: String, verify_seeks: bool) -> Result<(Playback, VideoInfo)> {
         let video_capture = VideoCapture::from_file(&video_path, 0)?;
         let frame_number = video_capture.get(cv::videoio::CAP_PROP_POS_FRAMES)? as u32;
         let frame_count = video_capture.get(cv::videoio::CAP_PROP_FRAME_COUNT)? as u32;
@@ -89,10 +203,30 @@ impl Playback {
             return Err(anyhow::anyhow!("Invalid video size"));
         }
 
+        let sampler: Box<dyn VideoSampler> =
+            match frame_count > 0 && std::path::Path::new(&video_path).is_file() {
+                true => {
+                    // Indexing reads the whole video, which takes a while for long videos
+                    let (index_tx, index_rx) = oneshot::channel();
+                    let path = video_path.clone();
+                    tokio::task::spawn_blocking(move || {
+                        let _ = index_tx.send(FrameIndex::open(&path));
+                    });
+                    Box::new(IndexedVideo {
+                        capture: video_capture,
+                        index: None,
+                        pending_index: Some(index_rx),
+                        verify: verify_seeks,
+                        position: frame_number,
+                        grabbed: false,
+                    })
+                }
+                false => Box::new(video_capture),
+            };
         Ok((
             Playback {
                 frame_number,
-                sampler: Box::new(video_capture),
+                sampler,
             },
             VideoInfo {
                 path: video_path,
@@ -205,6 +339,11 @@ impl VideoSampler for PylonCamera<'_> {
         Err(anyhow::anyhow!("Failed to retrieve frame"))
     }
 
+    fn timestamp(&self) -> Result<Option<f64>> {
+        // Camera ticks are nanoseconds
+        Ok(Some(self.grab_result.time_stamp()? as f64 / 1e9))
+    }
+
     fn set_exposure(&mut self, exposure: f64) -> Result<()> {
         self.camera
             .node_map()
@@ -216,7 +355,9 @@ impl VideoSampler for PylonCamera<'_> {
 
 impl VideoSampler for VideoCapture {
     fn get_image(&mut self, mat: &mut Mat) -> Result<()> {
-        self.read(mat)?;
+        if !self.read(mat)? {
+            return Err(anyhow::anyhow!("Failed to read frame"));
+        }
         Ok(())
     }
 
@@ -224,21 +365,209 @@ impl VideoSampler for VideoCapture {
         self.set(cv::videoio::CAP_PROP_POS_FRAMES, target_framenumber as f64)?;
         Ok(())
     }
+
+    fn timestamp(&self) -> Result<Option<f64>> {
+        // Positions of live streams are meaningless, they are timestamped with the wall clock
+        if self.get(cv::videoio::CAP_PROP_FRAME_COUNT)? <= 0.0 {
+            return Ok(None);
+        }
+        Ok(Some(self.get(cv::videoio::CAP_PROP_POS_MSEC)? / 1000.0))
+    }
 }
 
-impl VideoSampler for FakeDecoder {
-    fn get_image(&mut self, _: &mut Mat) -> Result<()> {
+impl IndexedVideo {
+    /// Takes over the index, once it was built.
+    fn poll_index(&mut self) -> Result<()> {
+        let result = match self.pending_index.as_mut().map(|rx| rx.try_recv()) {
+            Some(Ok(result)) => result,
+            Some(Err(oneshot::error::TryRecvError::Empty)) | None => return Ok(()),
+            Some(Err(oneshot::error::TryRecvError::Closed)) => {
+                Err(anyhow::anyhow!("Indexing was aborted"))
+            }
+        };
+        self.pending_index = None;
+        let index = match result {
+            Ok(index) => index,
+            Err(e) => {
+                log::warn!("Seeking will be inexact: {}", e);
+                return Ok(());
+            }
+        };
+        // Earlier seeks were inexact, the position follows the frame decoded last
+        if self.position > 0 {
+            let timestamp = self.capture.get(cv::videoio::CAP_PROP_POS_MSEC)?;
+            if let Some(frame_number) = index.frame_at(timestamp) {
+                self.position = frame_number + 1;
+            }
+        }
+        log::info!("Frame index ready, seeking is exact");
+        self.index = Some(index);
         Ok(())
     }
 
-    fn seek(&mut self, _: u32) -> Result<()> {
+    /// Checks, that the last grabbed frame is the one at `position`.
+    fn check_position(&self, index: &FrameIndex) -> Result<()> {
+        let timestamp = self.capture.get(cv::videoio::CAP_PROP_POS_MSEC)?;
+        let frame_number = index.frame_at(timestamp);
+        if frame_number == Some(self.position) {
+            return Ok(());
+        }
+        let message = format!(
+            "Expected frame {}, but decoded frame {:?} at {} ms",
+            self.position, frame_number, timestamp
+        );
+        match self.verify {
+            true => Err(anyhow::anyhow!(message)),
+            false => {
+                log::warn!("{}", message);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl VideoSampler for IndexedVideo {
+    fn get_image(&mut self, mat: &mut Mat) -> Result<()> {
+        self.poll_index()?;
+        let grabbed = std::mem::take(&mut self.grabbed) || self.capture.grab()?;
+        if !grabbed || !self.capture.retrieve(mat, 0)? {
+            return Err(anyhow::anyhow!("Failed to read frame"));
+        }
+        if let (true, Some(index)) = (self.verify, &self.index) {
+            self.check_position(index)?;
+        }
+        self.position += 1;
         Ok(())
     }
+
+    /// Seeks to the closest keyframe before the target, then decodes up to the target. OpenCV
+    /// may land a few frames after the keyframe, so earlier keyframes are tried, if it overshoots.
+    fn seek(&mut self, target_framenumber: u32) -> Result<()> {
+        self.poll_index()?;
+        self.grabbed = false;
+        let index = match &self.index {
+            Some(index) => index,
+            None if self.verify => {
+                return Err(anyhow::anyhow!(
+                    "Frame index is not ready yet, seeking would be inexact"
+                ));
+            }
+            None => {
+                self.capture
+                    .set(cv::videoio::CAP_PROP_POS_FRAMES, target_framenumber as f64)?;
+                self.position = target_framenumber;
+                return Ok(());
+            }
+        };
+        if target_framenumber >= index.frame_count() {
+            self.capture
+                .set(cv::videoio::CAP_PROP_POS_FRAMES, target_framenumber as f64)?;
+            self.position = target_framenumber;
+            return Ok(());
+        }
+        for keyframe in index.keyframes_before(target_framenumber) {
+            self.capture
+                .set(cv::videoio::CAP_PROP_POS_FRAMES, keyframe as f64)?;
+            if !self.capture.grab()? {
+                return Err(anyhow::anyhow!("Failed to decode frame {}", keyframe));
+            }
+            let timestamp = self.capture.get(cv::videoio::CAP_PROP_POS_MSEC)?;
+            if keyframe == 0 || !index.is_after(timestamp, target_framenumber) {
+                break;
+            }
+        }
+        while index.is_before(
+            self.capture.get(cv::videoio::CAP_PROP_POS_MSEC)?,
+            target_framenumber,
+        ) {
+            if !self.capture.grab()? {
+                return Err(anyhow::anyhow!(
+                    "Failed to decode frame {}",
+                    target_framenumber
+                ));
+            }
+        }
+        self.grabbed = true;
+        self.position = target_framenumber;
+        self.check_position(index)
+    }
+
+    fn timestamp(&self) -> Result<Option<f64>> {
+        let index = match &self.index {
+            Some(index) => index,
+            None => {
+                return Ok(Some(
+                    self.capture.get(cv::videoio::CAP_PROP_POS_MSEC)? / 1000.0,
+                ))
+            }
+        };
+        let timestamp = match self.position.checked_sub(1) {
+            Some(last_position) => index.timestamps.get(last_position as usize),
+            None => None,
+        };
+        Ok(timestamp.map(|timestamp| timestamp / 1000.0))
+    }
+
+    fn frame_count(&self) -> Option<u32> {
+        self.index.as_ref().map(|index| index.frame_count())
+    }
+
+    fn frame_number(&self) -> Option<u32> {
+        self.index.as_ref()?;
+        self.position.checked_sub(1)
+    }
+}
+
+impl VideoSampler for ImageSequence {
+    fn get_image(&mut self, mat: &mut Mat) -> Result<()> {
+        let path = self
+            .paths
+            .get(self.position)
+            .context("End of image sequence")?;
+        let image = read_image(path)?;
+        if mat.size()? != image.size()? {
+            return Err(anyhow::anyhow!(
+                "Image {} has size {:?}, expected {:?}",
+                path.display(),
+                image.size()?,
+                mat.size()?,
+            ));
+        }
+        // Copy into the existing buffer, which may be shared memory
+        image.copy_to(mat)?;
+        self.position += 1;
+        Ok(())
+    }
+
+    fn seek(&mut self, target_framenumber: u32) -> Result<()> {
+        if target_framenumber as usize > self.paths.len() {
+            return Err(anyhow::anyhow!(
+                "Frame {} is beyond the last image",
+                target_framenumber
+            ));
+        }
+        self.position = target_framenumber as usize;
+        Ok(())
+    }
+
+    fn timestamp(&self) -> Result<Option<f64>> {
+        Ok(self
+            .position
+            .checked_sub(1)
+            .and_then(|last_position| self.timestamps[last_position]))
+    }
 }
 
 impl VideoDecoder {
-    pub fn new(path: String, fps: f64, configs: &Vec<CameraConfig>) -> Result<Self> {
-        let (mut playback, info) = Playback::open(path.clone(), fps)?;
+    pub fn new(
+        path: String,
+        fps: f64,
+        configs: &Vec<CameraConfig>,
+        arena: &Option<Arena>,
+        verify_seeks: bool,
+        buffer_count: usize,
+    ) -> Result<Self> {
+        let (mut playback, info) = Playback::open(path.clone(), fps, arena, verify_seeks)?;
         let camera_config = Playback::get_camera_config(&path, configs);
         if let Some(camera_config) = &camera_config {
             playback.sampler.set_exposure(camera_config.exposure)?;
@@ -247,12 +576,11 @@ impl VideoDecoder {
             info,
             camera_config,
             playback,
-            buffer_manager: DoubleBuffer::new(),
+            buffer_manager: DoubleBuffer::with_capacity(buffer_count),
         })
     }
 
     pub fn get_image(&mut self, undistort_map: Option<UndistortMap>) -> Result<Image> {
-        let frame_number = self.playback.frame_number;
         let shared_image = self
             .buffer_manager
             .get_mut(self.info.width, self.info.height, 3)?;
@@ -264,6 +592,29 @@ impl VideoDecoder {
         } else {
             self.playback.sampler.get_image(&mut shared_image.mat)?;
         }
+        self.update_frame_count();
+        let frame_number = match self.playback.sampler.frame_number() {
+            // Frames decoded after inexact seeks are numbered correctly, once the index is ready
+            Some(frame_number) if frame_number != self.playback.frame_number => {
+                log::info!(
+                    "Corrected frame number {} to {}",
+                    self.playback.frame_number,
+                    frame_number
+                );
+                frame_number
+            }
+            _ => self.playback.frame_number,
+        };
+        let timestamp = match self.playback.sampler.timestamp()? {
+            Some(timestamp) => Some(timestamp),
+            // Live sources without timestamps of their own
+            None if self.info.frame_count == 0 => Some(
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)?
+                    .as_secs_f64(),
+            ),
+            None => None,
+        };
         let image = Image {
             stream_id: "Tracking".to_owned(),
             frame_number,
@@ -271,18 +622,151 @@ impl VideoDecoder {
             width: self.info.width,
             height: self.info.height,
             channels: 3,
+            timestamp,
         };
-        self.playback.frame_number += 1;
+        self.playback.frame_number = frame_number + 1;
         Ok(image)
     }
 
     pub fn seek(&mut self, target_framenumber: u32) -> Result<()> {
         self.playback.sampler.seek(target_framenumber)?;
         self.playback.frame_number = target_framenumber;
+        self.update_frame_count();
         Ok(())
     }
 
+    /// The frame count from the container metadata is an estimate, the frame index replaces it
+    /// once it is ready.
+    fn update_frame_count(&mut self) {
+        if let Some(frame_count) = self.playback.sampler.frame_count() {
+            self.info.frame_count = frame_count;
+        }
+    }
+
+    pub fn ground_truth(&self, track_start: u32) -> Result<Option<Track>> {
+        self.playback.sampler.ground_truth(track_start)
+    }
+
     pub fn end_of_stream(&self) -> bool {
         self.info.frame_count > 0 && self.playback.frame_number >= self.info.frame_count
     }
 }
+
+fn read_image(path: &std::path::Path) -> Result<Mat> {
+    let image = cv::imgcodecs::imread(&path.to_string_lossy(), cv::imgcodecs::IMREAD_COLOR)?;
+    if image.empty() {
+        return Err(anyhow::anyhow!("Failed to read image {}", path.display()));
+    }
+    Ok(image)
+}
+
+fn is_image_file(path: &std::path::Path) -> bool {
+    let extension = match path.extension().and_then(|e| e.to_str()) {
+        Some(extension) => extension.to_lowercase(),
+        None => return false,
+    };
+    ["png", "tif", "tiff", "jpg", "jpeg", "bmp"].contains(&extension.as_str())
+}
+
+/// Matches file names against patterns with `*` (any characters) and `?` (one character).
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    // Position after the last `*` in the pattern, and the name position it matched up to
+    let mut backtrack = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            p += 1;
+            backtrack = Some((p, n));
+        } else if let Some((star_p, star_n)) = backtrack {
+            p = star_p;
+            n = star_n + 1;
+            backtrack = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Sort key, which orders numbers in file names by value, e.g. `frame_9` before `frame_10`.
+fn natural_key(name: &str) -> Vec<(String, u64)> {
+    let mut key = vec![];
+    let mut text = String::new();
+    let mut chars = name.chars().peekable();
+    while let Some(c) = chars.next() {
+        if !c.is_ascii_digit() {
+            text.push(c);
+            continue;
+        }
+        let mut number = c.to_digit(10).unwrap() as u64;
+        while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+            number = number.saturating_mul(10).saturating_add(digit as u64);
+            chars.next();
+        }
+        key.push((std::mem::take(&mut text), number));
+    }
+    key.push((text, 0));
+    key
+}
+
+/// Reads timestamps in seconds from a CSV file with the columns file name and timestamp. The
+/// first line may be a header. Returns the timestamp of each image, if listed.
+fn read_timestamps(
+    csv_path: &std::path::Path,
+    image_paths: &[std::path::PathBuf],
+) -> Result<Vec<Option<f64>>> {
+    let content = std::fs::read_to_string(csv_path)
+        .with_context(|| format!("Failed to read {}", csv_path.display()))?;
+    let mut timestamps = std::collections::HashMap::new();
+    for (line_idx, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (file_name, timestamp) = line.split_once(',').with_context(|| {
+            format!(
+                "{}:{}: Expected two columns",
+                csv_path.display(),
+                line_idx + 1
+            )
+        })?;
+        let timestamp = match timestamp.trim().parse::<f64>() {
+            Ok(timestamp) => timestamp,
+            Err(_) if line_idx == 0 => continue,
+            Err(e) => {
+                return Err(anyhow::anyhow!(
+                    "{}:{}: Invalid timestamp: {}",
+                    csv_path.display(),
+                    line_idx + 1,
+                    e
+                ))
+            }
+        };
+        timestamps.insert(file_name.trim().to_owned(), timestamp);
+    }
+    Ok(image_paths
+        .iter()
+        .map(|path| {
+            let file_name = path.file_name()?.to_str()?;
+            timestamps.get(file_name).copied()
+        })
+        .collect())
+}
+
+/// Frame rate from the median interval between consecutive timestamps.
+fn frame_rate(timestamps: &[Option<f64>]) -> Option<f64> {
+    let mut intervals = timestamps
+        .windows(2)
+        .filter_map(|pair| Some(pair[1]? - pair[0]?))
+        .filter(|interval| *interval > 0.0)
+        .collect::<Vec<_>>();
+    if intervals.is_empty() {
+        return None;
+    }
+    intervals.sort_by(f64::total_cmp);
+    Some(1.0 / intervals[intervals.len() / 2])
+}
@@ -0,0 +1,78 @@
+use super::protocol::Features;
+use std::collections::HashMap;
+
+/// Entities which should be created or retired after a tracked frame.
+#[derive(Default, Debug)]
+pub struct LifecycleChanges {
+    pub births: usize,
+    pub deaths: Vec<u32>,
+}
+
+/// Creates entities for detections which stay unmatched, and retires entities which are not
+/// detected anymore. This is used when the number of animals in the arena is unknown.
+pub struct EntityLifecycle {
+    /// Unmatched detections start a new entity after this many frames
+    birth_frames: u32,
+    /// Entities are retired after not being detected for this many frames
+    death_frames: u32,
+    /// First frame of the current run of frames with unmatched detections, and the lowest number
+    /// of unmatched detections during the run
+    unmatched_since: Option<(u32, usize)>,
+    last_seen: HashMap<u32, u32>,
+}
+
+impl EntityLifecycle {
+    pub fn new(birth_frames: u32, death_frames: u32) -> Self {
+        Self {
+            birth_frames,
+            death_frames,
+            unmatched_since: None,
+            last_seen: HashMap::new(),
+        }
+    }
+
+    /// Forget the history, e.g. when a new track is recorded.
+    pub fn reset(&mut self) {
+        self.unmatched_since = None;
+        self.last_seen.clear();
+    }
+
+    pub fn update(&mut self, features: &Features, entity_ids: &[u32]) -> LifecycleChanges {
+        let frame_number = features.frame_number;
+        let mut changes = LifecycleChanges::default();
+
+        // Out-of-bounds features stay unmatched on purpose, they do not start new entities
+        let unmatched = features
+            .features
+            .iter()
+            .filter(|f| f.id.is_none() && !f.out_of_bounds.unwrap_or(false))
+            .count();
+        self.unmatched_since = match (unmatched, self.unmatched_since) {
+            (0, _) => None,
+            (count, None) => Some((frame_number, count)),
+            (count, Some((start, min_count))) => Some((start, count.min(min_count))),
+        };
+        if let Some((start, count)) = self.unmatched_since {
+            if frame_number.saturating_sub(start) + 1 >= self.birth_frames {
+                changes.births = count;
+                self.unmatched_since = None;
+            }
+        }
+
+        // Entities merged into the feature of another entity are still detected
+        for feature in features.features.iter() {
+            for id in feature.id.iter().chain(feature.merged_ids.iter()) {
+                self.last_seen.insert(*id, frame_number);
+            }
+        }
+        self.last_seen.retain(|id, _| entity_ids.contains(id));
+        for id in entity_ids {
+            // Entities which were never detected are counted from now on
+            let last_seen = *self.last_seen.entry(*id).or_insert(frame_number);
+            if frame_number.saturating_sub(last_seen) >= self.death_frames {
+                changes.deaths.push(*id);
+            }
+        }
+        changes
+    }
+}
@@ -2,12 +2,18 @@ use super::protocol::*;
 
 impl Features {
     pub fn switch_ids(&mut self, switch_request: &EntityIdSwitch) {
-        self.features.iter_mut().for_each(|f| {
-            if f.id == Some(switch_request.id1) {
-                f.id = Some(switch_request.id2);
-            } else if f.id == Some(switch_request.id2) {
-                f.id = Some(switch_request.id1);
+        let switch = |id: u32| {
+            if id == switch_request.id1 {
+                switch_request.id2
+            } else if id == switch_request.id2 {
+                switch_request.id1
+            } else {
+                id
             }
+        };
+        self.features.iter_mut().for_each(|f| {
+            f.id = f.id.map(switch);
+            f.merged_ids.iter_mut().for_each(|id| *id = switch(*id));
         });
     }
 }
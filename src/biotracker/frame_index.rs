@@ -0,0 +1,158 @@
+use anyhow::{Context, Result};
+use cv::{core::Vector, prelude::*, videoio::VideoCapture};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Timestamps of the same frame, read while indexing and while decoding, differ by rounding only.
+const TIMESTAMP_TOLERANCE_MS: f64 = 0.1;
+
+/// Presentation timestamps and keyframes of all frames of a compressed video, in display order.
+/// Frame numbers computed by OpenCV from the frame rate are imprecise for many codecs, the index
+/// identifies frames by their timestamps instead.
+#[derive(Serialize, Deserialize)]
+pub struct FrameIndex {
+    /// Size and modification time of the indexed video, to detect changed videos
+    video_size: u64,
+    video_modified: u64,
+    /// Presentation timestamp of each frame, in milliseconds
+    pub timestamps: Vec<f64>,
+    /// Frame numbers of keyframes, ascending
+    pub keyframes: Vec<u32>,
+}
+
+impl FrameIndex {
+    /// Loads the index cached next to the video, or builds it on first open and caches it.
+    pub fn open(video_path: &str) -> Result<Self> {
+        let (video_size, video_modified) = file_version(Path::new(video_path))?;
+        let cache_path = cache_path(video_path);
+        if let Ok(file) = std::fs::File::open(&cache_path) {
+            let reader = std::io::BufReader::new(file);
+            match serde_json::from_reader::<_, FrameIndex>(reader) {
+                Ok(index)
+                    if index.video_size == video_size && index.video_modified == video_modified =>
+                {
+                    return Ok(index);
+                }
+                Ok(_) => log::info!("Video changed, rebuilding {}", cache_path.display()),
+                Err(e) => log::warn!("Failed to read {}: {}", cache_path.display(), e),
+            }
+        }
+
+        log::info!("Building frame index for {}", video_path);
+        let (timestamps, keyframes) = read_packets(video_path)?;
+        let index = Self {
+            video_size,
+            video_modified,
+            timestamps,
+            keyframes,
+        };
+        // The index is still used, if the directory of the video is read-only
+        if let Err(e) = index.save(&cache_path) {
+            log::warn!("Failed to write {}: {}", cache_path.display(), e);
+        }
+        Ok(index)
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+
+    pub fn frame_count(&self) -> u32 {
+        self.timestamps.len() as u32
+    }
+
+    /// Keyframes at or before `frame_number`, the closest first. Frame 0 is always included, the
+    /// decoder positions the start of the video exactly.
+    pub fn keyframes_before(&self, frame_number: u32) -> impl Iterator<Item = u32> + '_ {
+        let end = self.keyframes.partition_point(|k| *k <= frame_number);
+        self.keyframes[..end]
+            .iter()
+            .rev()
+            .copied()
+            .filter(|k| *k > 0)
+            .chain(std::iter::once(0))
+    }
+
+    /// Frame with the presentation timestamp `timestamp`, in milliseconds.
+    pub fn frame_at(&self, timestamp: f64) -> Option<u32> {
+        let frame_number = self
+            .timestamps
+            .partition_point(|t| *t < timestamp - TIMESTAMP_TOLERANCE_MS);
+        let indexed = *self.timestamps.get(frame_number)?;
+        match (indexed - timestamp).abs() <= TIMESTAMP_TOLERANCE_MS {
+            true => Some(frame_number as u32),
+            false => None,
+        }
+    }
+
+    /// Is `timestamp` before the presentation timestamp of `frame_number`?
+    pub fn is_before(&self, timestamp: f64, frame_number: u32) -> bool {
+        timestamp < self.timestamps[frame_number as usize] - TIMESTAMP_TOLERANCE_MS
+    }
+
+    /// Is `timestamp` after the presentation timestamp of `frame_number`?
+    pub fn is_after(&self, timestamp: f64, frame_number: u32) -> bool {
+        timestamp > self.timestamps[frame_number as usize] + TIMESTAMP_TOLERANCE_MS
+    }
+}
+
+/// The index is cached as `<video>.frames.json`.
+fn cache_path(video_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.frames.json", video_path))
+}
+
+fn file_version(path: &Path) -> Result<(u64, u64)> {
+    let metadata = std::fs::metadata(path)
+        .with_context(|| format!("Failed to read metadata of {}", path.display()))?;
+    let modified = metadata
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    Ok((metadata.len(), modified))
+}
+
+/// Reads the encoded packets of the video without decoding them. Packets are stored in decoding
+/// order, which differs from the display order for codecs with B-frames.
+fn read_packets(video_path: &str) -> Result<(Vec<f64>, Vec<u32>)> {
+    let raw_mode = Vector::from_slice(&[cv::videoio::CAP_PROP_FORMAT, -1]);
+    let mut capture =
+        VideoCapture::from_file_with_params(video_path, cv::videoio::CAP_FFMPEG, &raw_mode)?;
+    if !capture.is_opened()? {
+        return Err(anyhow::anyhow!(
+            "Failed to open {} for indexing",
+            video_path
+        ));
+    }
+    let mut packets = vec![];
+    while capture.grab()? {
+        let timestamp = capture.get(cv::videoio::CAP_PROP_POS_MSEC)?;
+        let keyframe = capture.get(cv::videoio::CAP_PROP_LRF_HAS_KEY_FRAME)? != 0.0;
+        packets.push((timestamp, keyframe));
+    }
+    if packets.is_empty() {
+        return Err(anyhow::anyhow!("No frames found in {}", video_path));
+    }
+    packets.sort_by(|a, b| a.0.total_cmp(&b.0));
+    if packets
+        .windows(2)
+        .any(|pair| pair[1].0 - pair[0].0 <= TIMESTAMP_TOLERANCE_MS)
+    {
+        return Err(anyhow::anyhow!(
+            "Frames of {} have no distinct timestamps",
+            video_path
+        ));
+    }
+    let keyframes = packets
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, keyframe))| *keyframe)
+        .map(|(frame_number, _)| frame_number as u32)
+        .collect();
+    let timestamps = packets
+        .into_iter()
+        .map(|(timestamp, _)| timestamp)
+        .collect();
+    Ok((timestamps, keyframes))
+}
@@ -0,0 +1,119 @@
+/// Velocity variance of new filters, in (pixels/frame)². Large, because the velocity is unknown.
+const INITIAL_VELOCITY_VARIANCE: f64 = 100.0;
+
+/// Position and velocity along one axis, with their covariance.
+#[derive(Clone, Debug)]
+struct Axis {
+    position: f64,
+    velocity: f64,
+    covariance: [[f64; 2]; 2],
+}
+
+/// Constant velocity Kalman filter of a 2D position. Time is measured in frames. The axes are
+/// independent, because process and measurement noise are isotropic.
+#[derive(Clone, Debug)]
+pub struct KalmanFilter {
+    axes: [Axis; 2],
+    /// Variance of the acceleration between frames
+    process_noise: f64,
+    /// Variance of measured positions
+    measurement_noise: f64,
+}
+
+impl Axis {
+    fn new(position: f64, measurement_noise: f64) -> Self {
+        Self {
+            position,
+            velocity: 0.0,
+            covariance: [[measurement_noise, 0.0], [0.0, INITIAL_VELOCITY_VARIANCE]],
+        }
+    }
+
+    /// State and covariance after `dt` frames, with white noise acceleration.
+    fn predict(&self, dt: f64, process_noise: f64) -> Axis {
+        let [[p00, p01], [p10, p11]] = self.covariance;
+        let q = process_noise;
+        Axis {
+            position: self.position + dt * self.velocity,
+            velocity: self.velocity,
+            covariance: [
+                [
+                    p00 + dt * (p01 + p10) + dt * dt * p11 + q * dt.powi(4) / 4.0,
+                    p01 + dt * p11 + q * dt.powi(3) / 2.0,
+                ],
+                [p10 + dt * p11 + q * dt.powi(3) / 2.0, p11 + q * dt * dt],
+            ],
+        }
+    }
+
+    fn correct(&mut self, measurement: f64, measurement_noise: f64) {
+        let [[p00, p01], [p10, p11]] = self.covariance;
+        let innovation = measurement - self.position;
+        let innovation_variance = p00 + measurement_noise;
+        let (k0, k1) = (p00 / innovation_variance, p10 / innovation_variance);
+        self.position += k0 * innovation;
+        self.velocity += k1 * innovation;
+        self.covariance = [
+            [(1.0 - k0) * p00, (1.0 - k0) * p01],
+            [p10 - k1 * p00, p11 - k1 * p01],
+        ];
+    }
+}
+
+impl KalmanFilter {
+    pub fn new(x: f64, y: f64, process_noise: f64, measurement_noise: f64) -> Self {
+        Self {
+            axes: [
+                Axis::new(x, measurement_noise),
+                Axis::new(y, measurement_noise),
+            ],
+            process_noise,
+            measurement_noise,
+        }
+    }
+
+    pub fn position(&self) -> (f64, f64) {
+        (self.axes[0].position, self.axes[1].position)
+    }
+
+    /// Predicted position `dt` frames after the last update.
+    pub fn predict(&self, dt: f64) -> (f64, f64) {
+        let x = self.axes[0].predict(dt, self.process_noise);
+        let y = self.axes[1].predict(dt, self.process_noise);
+        (x.position, y.position)
+    }
+
+    /// Squared Mahalanobis distance of a measurement `dt` frames after the last update.
+    pub fn mahalanobis_squared(&self, x: f64, y: f64, dt: f64) -> f64 {
+        [x, y]
+            .iter()
+            .zip(&self.axes)
+            .map(|(measurement, axis)| {
+                let predicted = axis.predict(dt, self.process_noise);
+                let variance = predicted.covariance[0][0] + self.measurement_noise;
+                (measurement - predicted.position).powi(2) / variance
+            })
+            .sum()
+    }
+
+    /// Move to a position `dt` frames after the last update, keeping the velocity. The velocity
+    /// becomes more uncertain, because it is not measured.
+    pub fn pin(&mut self, x: f64, y: f64, dt: f64) {
+        for (position, axis) in [x, y].into_iter().zip(self.axes.iter_mut()) {
+            let velocity_variance = axis.predict(dt, self.process_noise).covariance[1][1];
+            *axis = Axis {
+                position,
+                velocity: axis.velocity,
+                covariance: [[self.measurement_noise, 0.0], [0.0, velocity_variance]],
+            };
+        }
+    }
+
+    /// Update with a position measured `dt` frames after the last update.
+    pub fn update(&mut self, x: f64, y: f64, dt: f64) {
+        for (measurement, axis) in [x, y].into_iter().zip(self.axes.iter_mut()) {
+            *axis = axis.predict(dt, self.process_noise);
+            axis.correct(measurement, self.measurement_noise);
+        }
+    }
+}
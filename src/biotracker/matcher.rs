@@ -1,25 +1,111 @@
-use super::protocol::*;
+use super::{kalman::KalmanFilter, protocol::*};
 pub use matcher_server::Matcher;
 use pathfinding::{kuhn_munkres::kuhn_munkres_min, matrix::Matrix};
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::f64::consts::PI;
 use std::sync::{Arc, Mutex};
 use tonic::{Request, Response, Status};
 
 const MAX_DISTANCE: i64 = 1000000;
+/// Squared Mahalanobis distances are small numbers, scale them before rounding to integer costs.
+const MAHALANOBIS_SCALE: f64 = 1000.0;
+/// Squared world space distances are scaled from cm² to mm² before rounding to integer costs.
+const WORLD_SCALE: f64 = 100.0;
 
-#[derive(Deserialize, Default, Clone)]
+#[derive(Deserialize, Clone)]
+#[serde(default)]
 pub struct MatcherConfig {
     ignore_nan: bool,
     ignore_out_of_bounds: bool,
     confidence_threshold_feature: f32,
     confidence_threshold_node: f32,
+    /// Match `world_nodes` instead of `image_nodes`. All distances are in centimeters instead of
+    /// pixels.
+    world_space: bool,
+    /// Variance of the acceleration of nodes between frames, in (pixels/frame²)² or (cm/frame²)²
+    process_noise: f64,
+    /// Variance of detected node positions, in pixels² or cm²
+    measurement_noise: f64,
+    /// Use the Mahalanobis distance to the predicted positions instead of the squared distance
+    mahalanobis: bool,
+    /// Features further from the predicted position are not assigned. In pixels or centimeters,
+    /// or in standard deviations with `mahalanobis`.
+    gating_radius: Option<f64>,
+    /// Entities which were not matched for more than this many frames are lost
+    lost_after: Option<u32>,
+    /// Lost entities are only reacquired by features within this many pixels or centimeters of
+    /// their last position
+    reacquisition_radius: Option<f64>,
+    /// Weight of the squared heading difference in radians, added to the squared node distances.
+    /// The heading points from the center node to the front node of the skeleton.
+    heading_weight: f64,
+    /// Flip features whose heading is reversed compared to the entity they are assigned to
+    correct_flips: bool,
+    /// Frames are flagged as ambiguous if any identity confidence is below this threshold
+    ambiguity_threshold: f32,
+    /// Unassigned entities predicted within this radius of a feature assigned to another entity
+    /// are merged into that feature. In pixels or centimeters, or in standard deviations with
+    /// `mahalanobis`.
+    merge_radius: Option<f64>,
+    /// Weight of the cosine distance between appearance embeddings, added to the cost of
+    /// reacquiring lost entities. In squared pixels or centimeters.
+    appearance_weight: f64,
+    /// Number of recent embeddings kept per entity
+    gallery_size: usize,
+}
+
+impl Default for MatcherConfig {
+    fn default() -> Self {
+        Self {
+            ignore_nan: false,
+            ignore_out_of_bounds: false,
+            confidence_threshold_feature: 0.0,
+            confidence_threshold_node: 0.0,
+            world_space: false,
+            process_noise: 1.0,
+            measurement_noise: 4.0,
+            mahalanobis: false,
+            gating_radius: None,
+            lost_after: None,
+            reacquisition_radius: None,
+            heading_weight: 0.0,
+            correct_flips: false,
+            ambiguity_threshold: 0.5,
+            merge_radius: None,
+            appearance_weight: 0.0,
+            gallery_size: 10,
+        }
+    }
+}
+
+impl MatcherConfig {
+    /// Nodes of a feature in the coordinate space used for matching.
+    fn nodes<'a>(&self, feature: &'a Feature) -> &'a Vec<SkeletonNode> {
+        nodes(feature, self.world_space)
+    }
+}
+
+/// Last matched feature of an entity, and the motion of each of its nodes.
+struct TrackedEntity {
+    feature: Feature,
+    filters: Vec<Option<KalmanFilter>>,
+    /// Are the filters following world space positions?
+    world_space: bool,
+    frame_number: u32,
+    /// Lost entities are not predicted anymore, they wait to be reacquired near their last position
+    lost: bool,
+    /// Entity whose feature this entity shares. Merged entities follow the shared feature with
+    /// frozen velocities, until they are assigned a feature of their own
+    merged_into: Option<u32>,
+    /// Recent appearance embeddings, which identify the entity after long absences
+    gallery: VecDeque<Vec<f32>>,
 }
 
 #[derive(Default)]
 struct MatcherState {
     config: MatcherConfig,
-    last_seen: HashMap<u32, Feature>,
+    last_seen: HashMap<u32, TrackedEntity>,
 }
 
 #[derive(Default)]
@@ -37,6 +123,7 @@ impl Matcher for MatcherService {
         let MatcherRequest {
             features,
             entity_ids,
+            skeleton,
         } = request;
         let features = match features {
             Some(f) => f,
@@ -47,9 +134,11 @@ impl Matcher for MatcherService {
             }
         };
         let mut state = self.inner.lock().unwrap();
-        Ok(Response::new(
-            state.hungarian_matching(entity_ids, features),
-        ))
+        Ok(Response::new(state.hungarian_matching(
+            entity_ids,
+            features,
+            skeleton.as_ref(),
+        )))
     }
 
     async fn switch_ids(
@@ -62,16 +151,33 @@ impl Matcher for MatcherService {
         let id1_known = state.last_seen.contains_key(&id1);
         let id2_known = state.last_seen.contains_key(&id2);
         if id1_known && id2_known {
-            let features1 = state.last_seen.remove(&id1).unwrap();
-            let features2 = state.last_seen.remove(&id2).unwrap();
-            state.last_seen.insert(id1, features2);
-            state.last_seen.insert(id2, features1);
+            let entity1 = state.last_seen.remove(&id1).unwrap();
+            let entity2 = state.last_seen.remove(&id2).unwrap();
+            state.last_seen.insert(id1, entity2);
+            state.last_seen.insert(id2, entity1);
         } else {
             eprintln!("Could not process ID switch for entity IDs that were not seen before");
         }
         Ok(Response::new(Empty {}))
     }
 
+    async fn heartbeat(&self, _: Request<Empty>) -> Result<Response<Empty>, Status> {
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn reset(&self, _: Request<Empty>) -> Result<Response<Empty>, Status> {
+        let mut state = self.inner.lock().unwrap();
+        state.last_seen.clear();
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn seed(&self, request: Request<Features>) -> Result<Response<Empty>, Status> {
+        let features = request.into_inner();
+        let mut state = self.inner.lock().unwrap();
+        state.seed(features);
+        Ok(Response::new(Empty {}))
+    }
+
     async fn set_config(
         &self,
         request: Request<ComponentConfig>,
@@ -80,16 +186,64 @@ impl Matcher for MatcherService {
         let config: MatcherConfig = serde_json::from_str(&config).map_err(|e| {
             Status::invalid_argument(format!("Could not parse config: {}", e.to_string()))
         })?;
+        let noise_valid = config.measurement_noise > 0.0 && config.process_noise >= 0.0;
+        if !noise_valid {
+            return Err(Status::invalid_argument(
+                "measurement_noise must be positive and process_noise must not be negative",
+            ));
+        }
         let mut inner = self.inner.lock().unwrap();
+        if config.world_space != inner.config.world_space {
+            // Motion in the previous coordinate space is meaningless, restart it from the last
+            // matched features.
+            for entity in inner.last_seen.values_mut() {
+                let lost = entity.lost;
+                let gallery = std::mem::take(&mut entity.gallery);
+                *entity = TrackedEntity::new(entity.feature.clone(), entity.frame_number, &config);
+                entity.lost = lost;
+                entity.gallery = gallery;
+            }
+        }
         (*inner).config = config;
         Ok(Response::new(Empty {}))
     }
 }
 
 impl MatcherState {
-    fn hungarian_matching(&mut self, entity_ids: Vec<u32>, mut features_msg: Features) -> Features {
+    /// Continue tracking from previously matched features, e.g. from a recorded track. Entities
+    /// without a feature are forgotten.
+    fn seed(&mut self, features_msg: Features) {
+        let frame_number = features_msg.frame_number;
+        self.last_seen.clear();
+        for feature in features_msg.features {
+            if let Some(id) = feature.id {
+                let entity = TrackedEntity::new(feature, frame_number, &self.config);
+                self.last_seen.insert(id, entity);
+            }
+        }
+    }
+
+    fn hungarian_matching(
+        &mut self,
+        entity_ids: Vec<u32>,
+        mut features_msg: Features,
+        skeleton: Option<&SkeletonDescriptor>,
+    ) -> Features {
         let config = &self.config;
         let frame_number = features_msg.frame_number;
+        let heading_nodes = skeleton
+            .map(|s| (s.front_index as usize, s.center_index as usize))
+            .filter(|(front, center)| front != center);
+        let cost = Cost {
+            confidence_threshold: config.confidence_threshold_node,
+            heading_nodes: heading_nodes.filter(|_| config.heading_weight > 0.0),
+            heading_weight: config.heading_weight,
+            flip: match (config.correct_flips, skeleton) {
+                (true, Some(skeleton)) => Flip::new(skeleton),
+                _ => None,
+            },
+            appearance_weight: 0.0,
+        };
         // Remove out-of-bound features and features containing NaN values
         let mut nan_count = 0;
         let mut oob_count = 0;
@@ -104,7 +258,7 @@ impl MatcherState {
                     result = false;
                 }
                 if config.ignore_nan {
-                    for node in f.image_nodes.iter() {
+                    for node in config.nodes(f).iter() {
                         if node.x.is_nan() || node.y.is_nan() {
                             nan_count += 1;
                             result = false;
@@ -137,79 +291,719 @@ impl MatcherState {
             );
         }
 
-        let last_matched_features = entity_ids
+        // Entities which are tracked are matched first, by their predicted position. This also
+        // predicts entities which were missed in previous frames.
+        let (tracked_ids, waiting_ids): (Vec<u32>, Vec<u32>) = entity_ids
+            .into_iter()
+            .partition(|id| self.last_seen.get(id).is_some_and(|entity| !entity.lost));
+        let predictions = tracked_ids
             .iter()
-            .map(|id| self.last_seen.get(id))
+            .map(|id| Some(self.last_seen[id].predict(frame_number, config.mahalanobis)))
             .collect::<Vec<_>>();
-        let last_matched_features_count = last_matched_features.len();
-
-        // Match features
-        let weights = distance_matrix(
-            &features,
-            &last_matched_features,
-            config.confidence_threshold_node,
-        );
-        let (_, assignment) = kuhn_munkres_min(&weights);
-        for (feature_idx, last_feature_idx) in assignment.iter().enumerate() {
-            if feature_idx >= features.len() || *last_feature_idx >= last_matched_features_count {
-                continue;
+        let gate = config
+            .gating_radius
+            .map(|radius| radius_cost(radius, config));
+        let (tracked_assignment, gated_count) = {
+            let candidates = features.iter().map(|f| &**f).collect::<Vec<_>>();
+            assign(&candidates, &predictions, &cost, gate)
+        };
+
+        // A single feature may explain two overlapping entities. Unassigned entities predicted
+        // close to an assigned feature are merged into it, the closest one if there are several.
+        // Merged entities stay with the feature of the entity they merged into.
+        let mut merges = vec![];
+        if let Some(radius) = config.merge_radius {
+            let merge_cost = radius_cost(radius, config);
+            for (prediction_idx, prediction) in predictions.iter().enumerate() {
+                let prediction = match prediction {
+                    Some(prediction) => prediction,
+                    None => continue,
+                };
+                if tracked_assignment
+                    .iter()
+                    .any(|(_, idx, _)| *idx == prediction_idx)
+                {
+                    continue;
+                }
+                let id = tracked_ids[prediction_idx];
+                let shared = match self.last_seen[&id].merged_into {
+                    Some(merged_into) => tracked_assignment
+                        .iter()
+                        .find(|(_, idx, _)| tracked_ids[*idx] == merged_into)
+                        .map(|(feature_idx, _, _)| *feature_idx),
+                    None => tracked_assignment
+                        .iter()
+                        .map(|(feature_idx, _, _)| {
+                            let distance = distance(features[*feature_idx], prediction, &cost);
+                            (*feature_idx, distance)
+                        })
+                        .filter(|(_, distance)| *distance <= merge_cost)
+                        .min_by_key(|(_, distance)| *distance)
+                        .map(|(feature_idx, _)| feature_idx),
+                };
+                if let Some(feature_idx) = shared {
+                    merges.push((feature_idx, id));
+                }
             }
-            let id = entity_ids[*last_feature_idx];
-            features[feature_idx].id = Some(id);
-            self.last_seen.insert(id, features[feature_idx].clone());
+        }
+
+        // The remaining features may reacquire lost entities near their last position, or be
+        // assigned to entities which were never seen.
+        let remaining_features = (0..features.len())
+            .filter(|feature_idx| {
+                !tracked_assignment
+                    .iter()
+                    .any(|(assigned_idx, _, _)| assigned_idx == feature_idx)
+            })
+            .collect::<Vec<_>>();
+        let remaining_predictions = waiting_ids
+            .iter()
+            .map(|id| {
+                self.last_seen
+                    .get(id)
+                    .map(|entity| entity.predict(entity.frame_number, false))
+            })
+            .collect::<Vec<_>>();
+        let waiting_assignment = {
+            let remaining = remaining_features
+                .iter()
+                .map(|feature_idx| &*features[*feature_idx])
+                .collect::<Vec<_>>();
+            let gate = config
+                .reacquisition_radius
+                .map(|radius| (radius * radius * distance_scale(config.world_space)) as i64);
+            // Positions of entities which were lost for long are unreliable, their appearance
+            // helps to tell them apart.
+            let reacquisition_cost = Cost {
+                appearance_weight: config.appearance_weight,
+                ..cost.clone()
+            };
+            let (assignment, _) = assign(
+                &remaining,
+                &remaining_predictions,
+                &reacquisition_cost,
+                gate,
+            );
+            assignment
+        };
+
+        let assignments = tracked_assignment
+            .into_iter()
+            .map(|(feature_idx, prediction_idx, confidence)| {
+                (feature_idx, tracked_ids[prediction_idx], confidence)
+            })
+            .chain(
+                waiting_assignment
+                    .into_iter()
+                    .map(|(idx, prediction_idx, confidence)| {
+                        let feature_idx = remaining_features[idx];
+                        (feature_idx, waiting_ids[prediction_idx], confidence)
+                    }),
+            )
+            .collect::<Vec<_>>();
+        let mut ambiguous_ids = vec![];
+        for (feature_idx, id, confidence) in assignments.iter() {
+            features[*feature_idx].id = Some(*id);
+            features[*feature_idx].id_confidence = *confidence;
+            if confidence.is_some_and(|confidence| confidence < config.ambiguity_threshold) {
+                ambiguous_ids.push(*id);
+            }
+            match self.last_seen.get_mut(id) {
+                Some(entity) => {
+                    if entity.lost {
+                        log::info!("Frame {} Reacquired entity {}", frame_number, id);
+                    }
+                    if let (Some((front, center)), Some(flip_nodes)) = (heading_nodes, &cost.flip) {
+                        let entity_heading = entity.heading(frame_number, front, center);
+                        let feature_heading = heading(
+                            config.nodes(features[*feature_idx]),
+                            front,
+                            center,
+                            config.confidence_threshold_node,
+                        );
+                        if let (Some(a), Some(b)) = (entity_heading, feature_heading) {
+                            if heading_difference(a, b) > PI / 2.0 {
+                                log::debug!("Frame {} Flipped entity {}", frame_number, id);
+                                flip(features[*feature_idx], flip_nodes);
+                            }
+                        }
+                    }
+                    entity.update(features[*feature_idx].clone(), frame_number, config);
+                }
+                None => {
+                    let feature = features[*feature_idx].clone();
+                    self.last_seen
+                        .insert(*id, TrackedEntity::new(feature, frame_number, config));
+                }
+            }
+        }
+
+        // Merged entities are pinned to the shared feature, but keep their velocities, so that
+        // their own motion is predicted after the split. They only split when they are assigned a
+        // feature of their own, or when the entity they merged into is lost.
+        for id in tracked_ids.iter() {
+            let shared = merges
+                .iter()
+                .find(|(_, merged_id)| merged_id == id)
+                .map(|(feature_idx, _)| *feature_idx);
+            let assigned = assignments
+                .iter()
+                .any(|(_, assigned_id, _)| assigned_id == id);
+            let merged_into = self.last_seen[id].merged_into;
+            let merged_into_tracked = merged_into.is_some_and(|merged_into| {
+                self.last_seen
+                    .get(&merged_into)
+                    .is_some_and(|entity| !entity.lost)
+            });
+            let entity = self.last_seen.get_mut(id).unwrap();
+            match (shared, merged_into) {
+                (Some(feature_idx), _) => {
+                    let feature = &mut *features[feature_idx];
+                    feature.merged_ids.push(*id);
+                    if merged_into.is_none() {
+                        log::info!(
+                            "Frame {} Merged entity {} into entity {:?}",
+                            frame_number,
+                            id,
+                            feature.id
+                        );
+                    }
+                    entity.pin(feature, frame_number, config);
+                    entity.merged_into = feature.id;
+                }
+                (None, Some(_)) if assigned => {
+                    log::info!("Frame {} Split entity {}", frame_number, id);
+                    entity.merged_into = None;
+                }
+                (None, Some(merged_into)) if !merged_into_tracked => {
+                    log::info!(
+                        "Frame {} Entity {} is no longer merged, entity {} was lost",
+                        frame_number,
+                        id,
+                        merged_into
+                    );
+                    entity.merged_into = None;
+                }
+                // Also while the shared feature is missed
+                (None, _) => {}
+            }
+        }
+
+        // Tracked entities which were missed for too long are lost, unless they are merged
+        let mut lost_count = 0;
+        if let Some(lost_after) = config.lost_after {
+            for id in tracked_ids.iter() {
+                let entity = self.last_seen.get_mut(id).unwrap();
+                if entity.merged_into.is_none()
+                    && entity.frames_since(frame_number) > lost_after as f64
+                {
+                    entity.lost = true;
+                    lost_count += 1;
+                    log::info!("Frame {} Lost entity {}", frame_number, id);
+                }
+            }
+        }
+        // Frames with nearly tied assignment costs are flagged, so they can be reviewed later
+        if !ambiguous_ids.is_empty() {
+            log::debug!(
+                "Frame {} Ambiguous assignment of entities {:?}",
+                frame_number,
+                ambiguous_ids
+            );
+            features_msg.ambiguous = true;
+            metrics::increment_counter!("count.ambiguous_frames");
+        }
+        if gated_count > 0 || lost_count > 0 {
+            metrics::counter!("count.gated_assignments", gated_count as u64);
+            metrics::counter!("count.lost_entities", lost_count as u64);
         }
 
         features_msg
     }
 }
 
-fn distance(a: &Feature, b: &Feature, confidence_threshold: f32) -> i64 {
+impl TrackedEntity {
+    fn new(feature: Feature, frame_number: u32, config: &MatcherConfig) -> Self {
+        let filters = config
+            .nodes(&feature)
+            .iter()
+            .map(|node| new_filter(node, config))
+            .collect();
+        let mut entity = Self {
+            feature,
+            filters,
+            world_space: config.world_space,
+            frame_number,
+            lost: false,
+            merged_into: None,
+            gallery: VecDeque::new(),
+        };
+        entity.add_to_gallery(config);
+        entity
+    }
+
+    /// Add the embedding of the last matched feature to the gallery.
+    fn add_to_gallery(&mut self, config: &MatcherConfig) {
+        if self.feature.embedding.is_empty() {
+            return;
+        }
+        self.gallery.push_back(self.feature.embedding.clone());
+        while self.gallery.len() > config.gallery_size {
+            self.gallery.pop_front();
+        }
+    }
+
+    /// Smallest cosine distance between the embedding of `feature` and the gallery.
+    fn appearance_distance(&self, feature: &Feature) -> Option<f64> {
+        self.gallery
+            .iter()
+            .filter_map(|embedding| cosine_distance(&feature.embedding, embedding))
+            .min_by(f64::total_cmp)
+    }
+
+    /// Number of frames between the last match and `frame_number`.
+    fn frames_since(&self, frame_number: u32) -> f64 {
+        frame_number.saturating_sub(self.frame_number) as f64
+    }
+
+    fn predict(&self, frame_number: u32, mahalanobis: bool) -> Prediction<'_> {
+        let dt = self.frames_since(frame_number);
+        let nodes = self
+            .filters
+            .iter()
+            .map(|filter| {
+                filter.as_ref().map(|filter| {
+                    let (x, y) = filter.predict(dt);
+                    (x as f32, y as f32)
+                })
+            })
+            .collect();
+        Prediction {
+            entity: self,
+            nodes,
+            dt,
+            mahalanobis,
+        }
+    }
+
+    /// Predicted heading in `frame_number`, from the center node to the front node.
+    fn heading(&self, frame_number: u32, front: usize, center: usize) -> Option<f64> {
+        let dt = self.frames_since(frame_number);
+        let front = self.filters.get(front)?.as_ref()?.predict(dt);
+        let center = self.filters.get(center)?.as_ref()?.predict(dt);
+        Some((front.1 - center.1).atan2(front.0 - center.0))
+    }
+
+    /// Move the filters to the nodes of a feature shared with another entity, keeping their
+    /// velocities. The feature and the gallery stay those of the entity.
+    fn pin(&mut self, feature: &Feature, frame_number: u32, config: &MatcherConfig) {
+        let dt = self.frames_since(frame_number);
+        let nodes = config.nodes(feature);
+        self.filters.resize(nodes.len(), None);
+        for (node, filter) in nodes.iter().zip(self.filters.iter_mut()) {
+            if !is_confident(node, config.confidence_threshold_node) {
+                continue;
+            }
+            match filter {
+                Some(filter) => filter.pin(node.x as f64, node.y as f64, dt),
+                None => *filter = new_filter(node, config),
+            }
+        }
+        self.frame_number = frame_number;
+    }
+
+    fn update(&mut self, feature: Feature, frame_number: u32, config: &MatcherConfig) {
+        let dt = self.frames_since(frame_number);
+        let nodes = config.nodes(&feature);
+        self.filters.resize(nodes.len(), None);
+        for (node, filter) in nodes.iter().zip(self.filters.iter_mut()) {
+            if !is_confident(node, config.confidence_threshold_node) {
+                continue;
+            }
+            match filter {
+                Some(filter) => filter.update(node.x as f64, node.y as f64, dt),
+                None => *filter = new_filter(node, config),
+            }
+        }
+        self.feature = feature;
+        self.frame_number = frame_number;
+        self.lost = false;
+        self.add_to_gallery(config);
+    }
+}
+
+fn new_filter(node: &SkeletonNode, config: &MatcherConfig) -> Option<KalmanFilter> {
+    if !is_confident(node, config.confidence_threshold_node) {
+        return None;
+    }
+    Some(KalmanFilter::new(
+        node.x as f64,
+        node.y as f64,
+        config.process_noise,
+        config.measurement_noise,
+    ))
+}
+
+/// Is the node detected, with a score of at least `confidence_threshold`?
+fn is_confident(node: &SkeletonNode, confidence_threshold: f32) -> bool {
+    !node.x.is_nan() && !node.y.is_nan() && node.score >= confidence_threshold
+}
+
+/// Heading of a feature, from the center node to the front node.
+fn heading(nodes: &[SkeletonNode], front: usize, center: usize, threshold: f32) -> Option<f64> {
+    let front = nodes.get(front).filter(|n| is_confident(n, threshold))?;
+    let center = nodes.get(center).filter(|n| is_confident(n, threshold))?;
+    Some(((front.y - center.y) as f64).atan2((front.x - center.x) as f64))
+}
+
+/// Absolute difference of two headings, between 0 and π.
+fn heading_difference(a: f64, b: f64) -> f64 {
+    let difference = (a - b).rem_euclid(2.0 * PI);
+    difference.min(2.0 * PI - difference)
+}
+
+/// Nodes to move, when a feature is turned around its center node.
+#[derive(Clone)]
+struct Flip {
+    center: usize,
+    /// Nodes in front of and behind the center, at the same distance from it along the skeleton
+    swapped: Vec<(usize, usize)>,
+    /// Nodes without a counterpart on the other side of the center, they are mirrored around it
+    mirrored: Vec<usize>,
+}
+
+impl Flip {
+    /// Follows the path through the skeleton from the front node to the node furthest from it.
+    /// The nodes in front of the center node are swapped with the nodes behind it, the center
+    /// node stays in place.
+    fn new(skeleton: &SkeletonDescriptor) -> Option<Self> {
+        let node_count = skeleton.node_names.len();
+        let front = skeleton.front_index as usize;
+        let center = skeleton.center_index as usize;
+        if front >= node_count || center >= node_count || front == center {
+            return None;
+        }
+        let mut previous: Vec<Option<usize>> = vec![None; node_count];
+        let mut visited = vec![false; node_count];
+        let mut queue = VecDeque::from([front]);
+        let mut last = front;
+        visited[front] = true;
+        while let Some(node) = queue.pop_front() {
+            last = node;
+            for edge in skeleton.edges.iter() {
+                let (source, target) = (edge.source as usize, edge.target as usize);
+                let neighbor = match node {
+                    _ if source == node => target,
+                    _ if target == node => source,
+                    _ => continue,
+                };
+                if neighbor < node_count && !visited[neighbor] {
+                    visited[neighbor] = true;
+                    previous[neighbor] = Some(node);
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+        if !visited[center] {
+            return None;
+        }
+        // Both sides are ordered by their distance from the center
+        let mut front_side = vec![];
+        let mut node = center;
+        while let Some(next) = previous[node] {
+            front_side.push(next);
+            node = next;
+        }
+        let mut back_side = vec![last];
+        while let Some(next) = previous[*back_side.last().unwrap()] {
+            back_side.push(next);
+        }
+        back_side = match back_side.iter().position(|node| *node == center) {
+            Some(position) => back_side[..position].iter().rev().copied().collect(),
+            // The furthest node is not behind the center node
+            None => vec![],
+        };
+        let swapped = front_side.iter().copied().zip(back_side.iter().copied());
+        let mirrored = match front_side.len() > back_side.len() {
+            true => &front_side[back_side.len()..],
+            false => &back_side[front_side.len()..],
+        };
+        Some(Self {
+            center,
+            swapped: swapped.collect(),
+            mirrored: mirrored.to_vec(),
+        })
+    }
+
+    /// Turn the nodes of a feature around.
+    fn apply(&self, nodes: &mut [SkeletonNode]) {
+        let center = match nodes.get(self.center) {
+            Some(center) => (center.x, center.y),
+            None => return,
+        };
+        let node_count = nodes.len();
+        if self.mirrored.iter().any(|idx| *idx >= node_count)
+            || self
+                .swapped
+                .iter()
+                .any(|(a, b)| *a >= node_count || *b >= node_count)
+        {
+            return;
+        }
+        for (a, b) in self.swapped.iter() {
+            nodes.swap(*a, *b);
+        }
+        for idx in self.mirrored.iter() {
+            let node = &mut nodes[*idx];
+            node.x = 2.0 * center.0 - node.x;
+            node.y = 2.0 * center.1 - node.y;
+        }
+    }
+}
+
+/// Turn a feature around, in image and world space.
+fn flip(feature: &mut Feature, flip: &Flip) {
+    flip.apply(&mut feature.image_nodes);
+    flip.apply(&mut feature.world_nodes);
+}
+
+/// Parameters of the cost of assigning a feature to a prediction.
+#[derive(Clone)]
+struct Cost {
+    /// Nodes with lower scores are ignored
+    confidence_threshold: f32,
+    /// Front and center node, if headings are compared
+    heading_nodes: Option<(usize, usize)>,
+    heading_weight: f64,
+    /// Features are turned around after assignment, if they are reversed. Both orientations
+    /// are compared then.
+    flip: Option<Flip>,
+    appearance_weight: f64,
+}
+
+/// Predicted node positions of an entity in the frame being matched.
+struct Prediction<'a> {
+    entity: &'a TrackedEntity,
+    nodes: Vec<Option<(f32, f32)>>,
+    /// Frames since the entity was last matched
+    dt: f64,
+    mahalanobis: bool,
+}
+
+fn nodes(feature: &Feature, world_space: bool) -> &Vec<SkeletonNode> {
+    match world_space {
+        true => &feature.world_nodes,
+        false => &feature.image_nodes,
+    }
+}
+
+/// Cost of a distance of `radius` to a prediction of a tracked entity.
+fn radius_cost(radius: f64, config: &MatcherConfig) -> i64 {
+    match config.mahalanobis {
+        true => (radius * radius * MAHALANOBIS_SCALE) as i64,
+        false => (radius * radius * distance_scale(config.world_space)) as i64,
+    }
+}
+
+/// Scale of squared distances, before they are rounded to integer costs.
+fn distance_scale(world_space: bool) -> f64 {
+    match world_space {
+        true => WORLD_SCALE,
+        false => 1.0,
+    }
+}
+
+fn distance(a: &Feature, b: &Prediction, cost: &Cost) -> i64 {
+    let a_nodes = nodes(a, b.entity.world_space);
+    let mut distance = oriented_distance(a_nodes, b, cost);
+    if let Some(flip) = &cost.flip {
+        // Only the compared nodes are flipped, not the whole feature
+        let mut flipped = a_nodes.clone();
+        flip.apply(&mut flipped);
+        distance = distance.min(oriented_distance(&flipped, b, cost));
+    }
+    if cost.appearance_weight > 0.0 {
+        if let Some(appearance_distance) = b.entity.appearance_distance(a) {
+            let scale = distance_scale(b.entity.world_space);
+            distance += (cost.appearance_weight * appearance_distance * scale) as i64;
+        }
+    }
+    distance
+}
+
+/// Cosine distance between two embeddings, between 0 and 2.
+fn cosine_distance(a: &[f32], b: &[f32]) -> Option<f64> {
+    if a.is_empty() || a.len() != b.len() {
+        return None;
+    }
+    let (mut dot, mut norm_a, mut norm_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b.iter()) {
+        let (x, y) = (*x as f64, *y as f64);
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return None;
+    }
+    Some(1.0 - dot / (norm_a.sqrt() * norm_b.sqrt()))
+}
+
+/// Mean squared distance of the nodes, and the cost of the heading difference.
+fn oriented_distance(a_nodes: &[SkeletonNode], b: &Prediction, cost: &Cost) -> i64 {
+    let world_space = b.entity.world_space;
+    let scale = match b.mahalanobis {
+        true => MAHALANOBIS_SCALE,
+        false => distance_scale(world_space),
+    };
     let mut node_squared_distance_sum = 0;
     let mut node_cnt = 0;
-    for node_idx in 0..a.image_nodes.len() {
-        let x1 = a.image_nodes[node_idx].x;
-        let y1 = a.image_nodes[node_idx].y;
-        let x2 = b.image_nodes[node_idx].x;
-        let y2 = b.image_nodes[node_idx].y;
-        if x1.is_nan() || y1.is_nan() || x2.is_nan() || y2.is_nan() {
-            continue;
-        }
-        if a.score < confidence_threshold || b.score < confidence_threshold {
+    for (node_idx, (node, predicted)) in a_nodes.iter().zip(b.nodes.iter()).enumerate() {
+        let (x1, y1) = (node.x, node.y);
+        let (x2, y2) = match *predicted {
+            Some(position) => position,
+            None => continue,
+        };
+        if !is_confident(node, cost.confidence_threshold) {
             continue;
         }
         node_cnt += 1;
-        let distance = (x1 - x2).powi(2) + (y1 - y2).powi(2);
+        let distance = match (b.mahalanobis, &b.entity.filters[node_idx]) {
+            (true, Some(filter)) => {
+                filter.mahalanobis_squared(x1 as f64, y1 as f64, b.dt) * MAHALANOBIS_SCALE
+            }
+            _ => ((x1 - x2).powi(2) + (y1 - y2).powi(2)) as f64 * scale,
+        };
         node_squared_distance_sum += distance as i64;
     }
-    match node_cnt {
-        0 => MAX_DISTANCE,
-        _ => node_squared_distance_sum / node_cnt,
+    if node_cnt == 0 {
+        return MAX_DISTANCE;
+    }
+    let mut distance = node_squared_distance_sum / node_cnt;
+    if let Some((front, center)) = cost.heading_nodes {
+        let feature_heading = heading(a_nodes, front, center, cost.confidence_threshold);
+        let predicted_heading = match (b.nodes.get(front), b.nodes.get(center)) {
+            (Some(Some(front)), Some(Some(center))) => {
+                Some(((front.1 - center.1) as f64).atan2((front.0 - center.0) as f64))
+            }
+            _ => None,
+        };
+        if let (Some(a), Some(b)) = (feature_heading, predicted_heading) {
+            distance += (cost.heading_weight * heading_difference(a, b).powi(2) * scale) as i64;
+        }
+    }
+    distance
+}
+
+/// Optimal assignment of features to predictions. Assignments which cost more than `gate` are
+/// rejected. Returns the feature index, prediction index and identity confidence of each
+/// assignment, and the number of rejected assignments.
+fn assign(
+    features: &[&Feature],
+    predictions: &[Option<Prediction>],
+    cost: &Cost,
+    gate: Option<i64>,
+) -> (Vec<(usize, usize, Option<f32>)>, usize) {
+    let mut weights = distance_matrix(features, predictions, cost);
+    // Gated pairs cost as much as leaving both unassigned. Entities which were never seen have
+    // no position and are never gated.
+    let is_gated = |feature_idx: usize, prediction_idx: usize, cost: i64| {
+        feature_idx < features.len()
+            && prediction_idx < predictions.len()
+            && predictions[prediction_idx].is_some()
+            && gate.is_some_and(|gate| cost > gate)
+    };
+    let mut gated = Matrix::new(weights.rows, weights.columns, false);
+    for feature_idx in 0..weights.rows {
+        for prediction_idx in 0..weights.columns {
+            let cost = weights[(feature_idx, prediction_idx)];
+            if is_gated(feature_idx, prediction_idx, cost) {
+                gated[(feature_idx, prediction_idx)] = true;
+                weights[(feature_idx, prediction_idx)] = MAX_DISTANCE;
+            }
+        }
+    }
+
+    let (_, assignment) = kuhn_munkres_min(&weights);
+    let mut gated_count = 0;
+    let mut result = vec![];
+    for (feature_idx, prediction_idx) in assignment.into_iter().enumerate() {
+        if feature_idx >= features.len() || prediction_idx >= predictions.len() {
+            continue;
+        }
+        if gated[(feature_idx, prediction_idx)] {
+            gated_count += 1;
+            continue;
+        }
+        // Entities which were never seen can not be confused with each other
+        let confidence = predictions[prediction_idx].is_some().then(|| {
+            identity_confidence(
+                &weights,
+                &gated,
+                features,
+                predictions,
+                feature_idx,
+                prediction_idx,
+            )
+        });
+        result.push((feature_idx, prediction_idx, confidence));
+    }
+    (result, gated_count)
+}
+
+/// Identity confidence of an assignment, from the margin between its cost and the cheapest
+/// alternative: another prediction for the same feature, or another feature for the same
+/// prediction. A confidence of 0 means the alternative is just as cheap, 1 means there is no
+/// plausible alternative.
+fn identity_confidence(
+    weights: &Matrix<i64>,
+    gated: &Matrix<bool>,
+    features: &[&Feature],
+    predictions: &[Option<Prediction>],
+    feature_idx: usize,
+    prediction_idx: usize,
+) -> f32 {
+    let assigned_cost = weights[(feature_idx, prediction_idx)];
+    let other_predictions = (0..predictions.len())
+        .filter(|idx| *idx != prediction_idx && predictions[*idx].is_some())
+        .map(|idx| (feature_idx, idx));
+    let other_features = (0..features.len())
+        .filter(|idx| *idx != feature_idx)
+        .map(|idx| (idx, prediction_idx));
+    let alternative_cost = other_predictions
+        .chain(other_features)
+        .filter(|pair| !gated[*pair])
+        .map(|pair| weights[pair])
+        .min();
+    match alternative_cost {
+        None => 1.0,
+        Some(alternative_cost) if alternative_cost <= 0 => 0.0,
+        Some(alternative_cost) => {
+            let margin = (alternative_cost - assigned_cost) as f64 / alternative_cost as f64;
+            margin.clamp(0.0, 1.0) as f32
+        }
     }
 }
 
 fn distance_matrix(
-    features: &Vec<&mut Feature>,
-    last_features: &Vec<Option<&Feature>>,
-    confidence_threshold: f32,
+    features: &[&Feature],
+    predictions: &[Option<Prediction>],
+    cost: &Cost,
 ) -> Matrix<i64> {
-    let n = features.len().max(last_features.len());
+    let n = features.len().max(predictions.len());
     let mut distances = Matrix::new(n, n, 0);
     for feature_idx in 0..n {
-        for last_feature_idx in 0..n {
-            let distance_ref = distances.get_mut((feature_idx, last_feature_idx)).unwrap();
-            let last_feature = if last_feature_idx >= last_features.len() {
+        for prediction_idx in 0..n {
+            let distance_ref = distances.get_mut((feature_idx, prediction_idx)).unwrap();
+            let prediction = if prediction_idx >= predictions.len() {
                 None
             } else {
-                last_features[last_feature_idx]
+                predictions[prediction_idx].as_ref()
             };
-            *distance_ref = if feature_idx >= features.len() || last_feature.is_none() {
+            *distance_ref = if feature_idx >= features.len() || prediction.is_none() {
                 MAX_DISTANCE
             } else {
-                distance(
-                    &features[feature_idx],
-                    last_feature.unwrap(),
-                    confidence_threshold,
-                )
+                distance(features[feature_idx], prediction.unwrap(), cost)
             }
         }
     }
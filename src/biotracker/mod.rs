@@ -1,21 +1,32 @@
 pub mod arena;
+pub mod batch;
 pub mod biotracker;
+pub mod blob_detector;
 pub mod channel;
 pub mod cli;
 pub mod component;
 pub mod decoder;
 pub mod encoder;
+pub mod entity_lifecycle;
 pub mod features;
+pub mod frame_index;
+pub mod kalman;
 pub mod logger;
 pub mod matcher;
 pub mod metrics_recorder;
 pub mod observer;
+pub mod onnx_detector;
+pub mod playback;
 pub mod port;
+pub mod process;
 pub mod protocol;
-pub mod python_process;
+pub mod rematch;
 pub mod service;
 pub mod shared_buffer;
+pub mod simulator;
+pub mod sort_matcher;
 pub mod state;
+pub mod subscription;
 pub mod tracking;
 pub mod undistort;
 
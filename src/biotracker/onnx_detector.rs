@@ -0,0 +1,293 @@
+use super::{protocol::*, DoubleBuffer};
+use anyhow::{bail, Context, Result};
+use cv::{core::Size, prelude::*};
+pub use feature_detector_server::FeatureDetector;
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use tonic::{Request, Response, Status};
+use tract_onnx::prelude::*;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TensorLayout {
+    /// Batch, height, width, channels, as used by SLEAP
+    Nhwc,
+    /// Batch, channels, height, width, as used by PyTorch models
+    Nchw,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct OnnxDetectorConfig {
+    model_path: String,
+    /// Input size of the model. Frames are resized to it.
+    input_width: u32,
+    input_height: u32,
+    #[serde(default)]
+    grayscale: bool,
+    /// Layout of both the input image and the output confidence maps
+    #[serde(default = "default_layout")]
+    layout: TensorLayout,
+    /// Pixel values are multiplied by this before inference
+    #[serde(default = "default_input_scale")]
+    input_scale: f32,
+    /// Peaks in confidence maps below this are ignored
+    #[serde(default = "default_peak_threshold")]
+    peak_threshold: f32,
+    /// One confidence map per node, in the order of the node names
+    skeleton: SkeletonDescriptor,
+}
+
+fn default_layout() -> TensorLayout {
+    TensorLayout::Nhwc
+}
+
+fn default_input_scale() -> f32 {
+    1.0
+}
+
+fn default_peak_threshold() -> f32 {
+    0.2
+}
+
+struct OnnxDetectorState {
+    config: OnnxDetectorConfig,
+    model: TypedRunnableModel<TypedModel>,
+    image_buffers: DoubleBuffer,
+}
+
+/// Built-in feature detector, which runs pose estimation models exported to ONNX on the CPU.
+#[derive(Default)]
+pub struct OnnxDetectorService {
+    inner: Arc<Mutex<Option<OnnxDetectorState>>>,
+}
+
+#[tonic::async_trait]
+impl FeatureDetector for OnnxDetectorService {
+    async fn detect_features(
+        &self,
+        request: Request<Image>,
+    ) -> Result<Response<DetectorResponse>, Status> {
+        let image = request.into_inner();
+        let inner = self.inner.clone();
+        // Inference would block the runtime of the core
+        let response = tokio::task::spawn_blocking(move || match &mut *inner.lock().unwrap() {
+            Some(state) => state.detect(&image).map(|features| DetectorResponse {
+                features: Some(features),
+                skeleton: Some(state.config.skeleton.clone()),
+            }),
+            None => bail!("No model loaded"),
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(|e| Status::internal(format!("Pose estimation failed: {}", e)))?;
+        Ok(Response::new(response))
+    }
+
+    async fn set_config(
+        &self,
+        request: Request<ComponentConfig>,
+    ) -> Result<Response<Empty>, Status> {
+        let config = request.into_inner().config_json;
+        let config: OnnxDetectorConfig = serde_json::from_str(&config).map_err(|e| {
+            Status::invalid_argument(format!("Could not parse config: {}", e.to_string()))
+        })?;
+        let state = tokio::task::spawn_blocking(move || OnnxDetectorState::new(config))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(|e| Status::invalid_argument(format!("Could not load model: {:#}", e)))?;
+        *self.inner.lock().unwrap() = Some(state);
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn heartbeat(&self, _: Request<Empty>) -> Result<Response<Empty>, Status> {
+        Ok(Response::new(Empty {}))
+    }
+}
+
+impl OnnxDetectorState {
+    fn new(config: OnnxDetectorConfig) -> Result<Self> {
+        let channels = if config.grayscale { 1 } else { 3 };
+        let (width, height) = (config.input_width as usize, config.input_height as usize);
+        let shape = match config.layout {
+            TensorLayout::Nhwc => [1, height, width, channels],
+            TensorLayout::Nchw => [1, channels, height, width],
+        };
+        let model = tract_onnx::onnx()
+            .model_for_path(&config.model_path)
+            .with_context(|| format!("Failed to read {}", config.model_path))?
+            .with_input_fact(0, f32::fact(shape).into())?
+            .into_optimized()?
+            .into_runnable()?;
+        Ok(Self {
+            config,
+            model,
+            image_buffers: DoubleBuffer::new(),
+        })
+    }
+
+    fn detect(&mut self, image: &Image) -> Result<Features> {
+        let input = self.input_tensor(image)?;
+        let outputs = self.model.run(tvec!(input.into()))?;
+        let maps = outputs[0].to_array_view::<f32>()?;
+        if maps.ndim() != 4 {
+            bail!(
+                "Expected confidence maps, got output of shape {:?}",
+                maps.shape()
+            );
+        }
+        let maps = match self.config.layout {
+            TensorLayout::Nhwc => maps.permuted_axes(&[0, 3, 1, 2][..]),
+            TensorLayout::Nchw => maps,
+        };
+        let maps = maps.into_dimensionality::<tract_ndarray::Ix4>()?;
+        let shape = maps.shape().to_owned();
+        if shape[1] != self.config.skeleton.node_names.len() {
+            bail!(
+                "Expected one confidence map per node, got output of shape {:?}",
+                shape
+            );
+        }
+        let (map_height, map_width) = (shape[2], shape[3]);
+        let scale_x = image.width as f32 / map_width as f32;
+        let scale_y = image.height as f32 / map_height as f32;
+        let mut peaks = vec![];
+        for node in 0..shape[1] {
+            let map = maps.slice(tract_ndarray::s![0, node, .., ..]);
+            let node_peaks = find_peaks(&map, self.config.peak_threshold)
+                .into_iter()
+                .map(|(x, y, score)| SkeletonNode {
+                    x: x * scale_x,
+                    y: y * scale_y,
+                    score,
+                })
+                .collect::<Vec<_>>();
+            peaks.push(node_peaks);
+        }
+        Ok(Features {
+            features: group_instances(&peaks, self.config.skeleton.center_index as usize),
+            frame_number: image.frame_number,
+            ..Default::default()
+        })
+    }
+
+    fn input_tensor(&mut self, image: &Image) -> Result<Tensor> {
+        let config = &self.config;
+        let frame = &self.image_buffers.get(image)?.mat;
+        let mut resized = Mat::default();
+        cv::imgproc::resize(
+            frame,
+            &mut resized,
+            Size::new(config.input_width as i32, config.input_height as i32),
+            0.0,
+            0.0,
+            cv::imgproc::INTER_LINEAR,
+        )?;
+        let mut converted = Mat::default();
+        let conversion = match config.grayscale {
+            true => cv::imgproc::COLOR_BGR2GRAY,
+            false => cv::imgproc::COLOR_BGR2RGB,
+        };
+        cv::imgproc::cvt_color(&resized, &mut converted, conversion, 0)?;
+        let data = converted.data_bytes()?;
+        let channels = converted.channels() as usize;
+        let (width, height) = (config.input_width as usize, config.input_height as usize);
+        let scale = config.input_scale;
+        let tensor: Tensor = match config.layout {
+            TensorLayout::Nhwc => tract_ndarray::Array4::from_shape_fn(
+                (1, height, width, channels),
+                |(_, y, x, c)| data[(y * width + x) * channels + c] as f32 * scale,
+            )
+            .into(),
+            TensorLayout::Nchw => tract_ndarray::Array4::from_shape_fn(
+                (1, channels, height, width),
+                |(_, c, y, x)| data[(y * width + x) * channels + c] as f32 * scale,
+            )
+            .into(),
+        };
+        Ok(tensor)
+    }
+}
+
+/// Local maxima of a confidence map above the threshold, refined to subpixel accuracy by the
+/// weighted mean of their neighbourhood. Returns x, y and score in map coordinates.
+fn find_peaks(map: &tract_ndarray::ArrayView2<f32>, threshold: f32) -> Vec<(f32, f32, f32)> {
+    let (height, width) = map.dim();
+    let mut peaks = vec![];
+    for y in 0..height {
+        for x in 0..width {
+            let value = map[[y, x]];
+            if value < threshold {
+                continue;
+            }
+            let mut is_peak = true;
+            let (mut sum, mut sum_x, mut sum_y) = (0.0, 0.0, 0.0);
+            for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                    let neighbour = map[[ny, nx]];
+                    // Plateaus are reported once, at their first pixel
+                    if neighbour > value || (neighbour == value && (ny, nx) < (y, x)) {
+                        is_peak = false;
+                    }
+                    let weight = neighbour.max(0.0);
+                    sum += weight;
+                    sum_x += weight * nx as f32;
+                    sum_y += weight * ny as f32;
+                }
+            }
+            if is_peak {
+                peaks.push((sum_x / sum, sum_y / sum, value));
+            }
+        }
+    }
+    peaks
+}
+
+/// Group node peaks into instances. Each peak of the center node starts an instance, the other
+/// nodes are assigned greedily to the closest instance by descending score. Nodes, which were not
+/// found for an instance, are NaN.
+fn group_instances(peaks: &[Vec<SkeletonNode>], center_index: usize) -> Vec<Feature> {
+    let missing = SkeletonNode {
+        x: f32::NAN,
+        y: f32::NAN,
+        score: 0.0,
+    };
+    let centers = match peaks.get(center_index) {
+        Some(centers) => centers,
+        None => return vec![],
+    };
+    let mut features: Vec<Feature> = centers
+        .iter()
+        .map(|center| {
+            let mut image_nodes = vec![missing.clone(); peaks.len()];
+            image_nodes[center_index] = center.clone();
+            Feature {
+                image_nodes,
+                score: center.score,
+                ..Default::default()
+            }
+        })
+        .collect();
+    for (node_index, node_peaks) in peaks.iter().enumerate() {
+        if node_index == center_index {
+            continue;
+        }
+        let mut node_peaks: Vec<&SkeletonNode> = node_peaks.iter().collect();
+        node_peaks.sort_by(|a, b| b.score.total_cmp(&a.score));
+        for peak in node_peaks {
+            let closest = features
+                .iter_mut()
+                .filter(|f| f.image_nodes[node_index].x.is_nan())
+                .min_by(|a, b| {
+                    let center_a = &a.image_nodes[center_index];
+                    let center_b = &b.image_nodes[center_index];
+                    let distance_a = (center_a.x - peak.x).hypot(center_a.y - peak.y);
+                    let distance_b = (center_b.x - peak.x).hypot(center_b.y - peak.y);
+                    distance_a.total_cmp(&distance_b)
+                });
+            if let Some(feature) = closest {
+                feature.image_nodes[node_index] = peak.clone();
+            }
+        }
+    }
+    features
+}
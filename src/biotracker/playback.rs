@@ -0,0 +1,76 @@
+use std::time::Duration;
+use tokio::time::{Instant, Sleep};
+
+/// Longer intervals between timestamps are treated as discontinuities, e.g. from seeking.
+const MAX_FRAME_INTERVAL: f64 = 1.0;
+
+/// Decides when the next frame is decoded during playback. Frames are due at the target fps,
+/// unless the video has timestamps: then the schedule follows the timestamps, so that videos with
+/// a variable frame rate play at their original pace.
+pub struct PlaybackClock {
+    frame_interval: Duration,
+    next_frame: Instant,
+    /// Start and timestamp of the frame the current schedule is relative to
+    anchor: Option<(Instant, f64)>,
+    last_timestamp: Option<f64>,
+}
+
+impl PlaybackClock {
+    pub fn new(fps: f32) -> Self {
+        Self {
+            frame_interval: Duration::from_secs_f64(1.0 / fps as f64),
+            next_frame: Instant::now(),
+            anchor: None,
+            last_timestamp: None,
+        }
+    }
+
+    pub fn set_fps(&mut self, fps: f32) {
+        self.frame_interval = Duration::from_secs_f64(1.0 / fps as f64);
+        self.reset();
+    }
+
+    /// Start a new schedule with the next decoded frame, e.g. after seeking or pausing.
+    pub fn reset(&mut self) {
+        self.anchor = None;
+        self.last_timestamp = None;
+    }
+
+    /// Resolves, when the next frame is due.
+    pub fn tick(&self) -> Sleep {
+        tokio::time::sleep_until(self.next_frame)
+    }
+
+    /// Without timestamps, the following frame is due one frame interval later.
+    pub fn advance(&mut self) {
+        self.next_frame += self.frame_interval;
+    }
+
+    /// Schedules the next frame after a frame, which was started at `started`, was decoded.
+    /// The next frame is expected after the same interval as the last one. `speed` is the ratio
+    /// of the target fps to the fps of the video.
+    pub fn frame_decoded(&mut self, started: Instant, timestamp: Option<f64>, speed: f64) {
+        let timestamp = match timestamp {
+            Some(timestamp) => timestamp,
+            None => return,
+        };
+        let interval = match self.last_timestamp.replace(timestamp) {
+            Some(last) if timestamp > last && timestamp - last <= MAX_FRAME_INTERVAL => {
+                timestamp - last
+            }
+            _ => {
+                self.anchor = Some((started, timestamp));
+                return;
+            }
+        };
+        let (anchor_start, anchor_timestamp) = *self.anchor.get_or_insert((started, timestamp));
+        let since_anchor = (timestamp + interval - anchor_timestamp) / speed;
+        self.next_frame = anchor_start + Duration::from_secs_f64(since_anchor);
+        if self.next_frame < Instant::now() {
+            // Playback fell behind, e.g. because tracking is slow. Catching up would play a burst
+            // of frames, so the schedule starts over.
+            self.anchor = Some((started, timestamp));
+            self.next_frame = started + Duration::from_secs_f64(interval / speed);
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use anyhow::{Context, Result};
+
+use super::{ComponentConfig, ProcessConfig, PythonConfig};
+use std::collections::VecDeque;
+use std::os::unix::process::CommandExt;
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::oneshot;
+
+/// Time a component has to shut down after SIGTERM, before it is killed.
+const STOP_GRACE_PERIOD: Duration = Duration::from_secs(5);
+/// Number of stderr lines which are kept for display after the process exited.
+const STDERR_TAIL_LINES: usize = 10;
+
+/// A component, which runs as a separate process. It serves its gRPC services on the address in
+/// `BIOTRACKER_COMPONENT_ADDRESS`.
+pub struct ComponentProcess {
+    stdout_log_task: tokio::task::JoinHandle<Result<()>>,
+    stderr_log_task: tokio::task::JoinHandle<Result<()>>,
+    wait_task: tokio::task::JoinHandle<Result<ExitStatus>>,
+    // The child is the leader of its own process group, which has the same id. None, once the
+    // group was killed after the child exited.
+    process_group: Arc<Mutex<Option<i32>>>,
+    exit_status: Arc<Mutex<Option<ExitStatus>>>,
+    stderr_tail: Arc<Mutex<VecDeque<String>>>,
+    id: String,
+}
+
+impl ComponentProcess {
+    pub fn start(
+        config: &ComponentConfig,
+        process_config: &ProcessConfig,
+        address: String,
+    ) -> Result<Self> {
+        // The process group is set on the std command, tokio supports it only since 1.37
+        let mut std_command = std::process::Command::new(&process_config.program);
+        std_command.process_group(0);
+        let mut command = Command::from(std_command);
+        command
+            .args(&process_config.args)
+            .envs(&process_config.env)
+            .env("BIOTRACKER_COMPONENT_ADDRESS", address)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        if !process_config.working_directory.is_empty() {
+            command.current_dir(&process_config.working_directory);
+        }
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("Failed to start {}", process_config.program))?;
+        let process_group = Arc::new(Mutex::new(child.id().map(|pid| pid as i32)));
+        let mut stdout_reader =
+            BufReader::new(child.stdout.take().context("stdout not available")?).lines();
+        let mut stderr_reader =
+            BufReader::new(child.stderr.take().context("stderr not available")?).lines();
+        let stdout_id = config.id.clone();
+        let stdout_log_task = tokio::spawn(async move {
+            while let Some(line) = stdout_reader.next_line().await? {
+                log::info!("{}: {}", stdout_id, line);
+            }
+            Ok(())
+        });
+        let stderr_id = config.id.clone();
+        let stderr_tail = Arc::new(Mutex::new(VecDeque::new()));
+        let tail = stderr_tail.clone();
+        let stderr_log_task = tokio::spawn(async move {
+            while let Some(line) = stderr_reader.next_line().await? {
+                log::warn!("{}: {}", stderr_id, line);
+                let mut tail = tail.lock().unwrap();
+                if tail.len() >= STDERR_TAIL_LINES {
+                    tail.pop_front();
+                }
+                tail.push_back(line);
+            }
+            Ok(())
+        });
+        let id = config.id.clone();
+        let exit_status = Arc::new(Mutex::new(None));
+        let status_slot = exit_status.clone();
+        let group = process_group.clone();
+        let wait_task = tokio::spawn(async move {
+            // The exited child is only reaped after its group was killed. Until then, the group id
+            // cannot be reused.
+            let exited = match child.id() {
+                Some(pid) => {
+                    let (exited_tx, exited_rx) = oneshot::channel();
+                    std::thread::spawn(move || {
+                        let _ = exited_tx.send(wait_for_exit(pid));
+                    });
+                    exited_rx.await?
+                }
+                None => Err(std::io::Error::other("Process was already reaped")),
+            };
+            let process_group = group.lock().unwrap().take();
+            match (exited, process_group) {
+                // Children which ignored SIGTERM, or were left behind by the process, e.g. after
+                // a crash, share its group.
+                (Ok(_), Some(process_group)) => signal_group(process_group, libc::SIGKILL),
+                (Err(e), _) => log::warn!("{}: Failed to wait for process: {}", id, e),
+                _ => {}
+            }
+            let status = child.wait().await?;
+            log::info!("{}: Process exited with {}.", id, status);
+            *status_slot.lock().unwrap() = Some(status);
+            Ok(status)
+        });
+
+        Ok(Self {
+            stdout_log_task,
+            stderr_log_task,
+            wait_task,
+            process_group,
+            exit_status,
+            stderr_tail,
+            id: config.id.clone(),
+        })
+    }
+
+    pub fn has_exited(&self) -> bool {
+        self.wait_task.is_finished()
+    }
+
+    /// Exit status of the process, if it exited.
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        *self.exit_status.lock().unwrap()
+    }
+
+    /// The last lines the process wrote to stderr.
+    pub fn stderr_tail(&self) -> Vec<String> {
+        self.stderr_tail.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Describes why the process exited, including its last lines of stderr.
+    pub fn exit_message(&self) -> String {
+        let mut message = match self.exit_status() {
+            Some(status) => format!("Process exited with {}", status),
+            None => "Process exited".to_owned(),
+        };
+        for line in self.stderr_tail() {
+            message = format!("{}\n{}", message, line);
+        }
+        message
+    }
+
+    /// Stop the process and all of its children. They are asked to terminate with SIGTERM first,
+    /// and killed, if they did not exit within the grace period.
+    pub async fn stop(&mut self) {
+        if !self.has_exited() {
+            self.signal(libc::SIGTERM);
+            match tokio::time::timeout(STOP_GRACE_PERIOD, &mut self.wait_task).await {
+                Ok(_) => {}
+                Err(_) => {
+                    log::warn!(
+                        "{}: Process did not stop within {}s, killing it",
+                        self.id,
+                        STOP_GRACE_PERIOD.as_secs()
+                    );
+                    if let Err(e) = self.kill().await {
+                        log::warn!("Failed to kill process {}: {}", self.id, e);
+                    }
+                }
+            }
+        }
+        self.stdout_log_task.abort();
+        self.stderr_log_task.abort();
+    }
+
+    async fn kill(&mut self) -> Result<()> {
+        self.signal(libc::SIGKILL);
+        tokio::time::timeout(Duration::from_secs(1), &mut self.wait_task)
+            .await
+            .context("Process did not exit after SIGKILL")???;
+        Ok(())
+    }
+
+    /// Send a signal to the whole process group of the child, unless it was already killed.
+    fn signal(&self, signal: libc::c_int) {
+        // The lock keeps the child from being reaped, while the group is signalled
+        if let Some(process_group) = *self.process_group.lock().unwrap() {
+            signal_group(process_group, signal);
+        }
+    }
+}
+
+/// Send a signal to a process group. Its leader must not have been reaped yet, otherwise the group
+/// id may belong to another group by now.
+fn signal_group(process_group: i32, signal: libc::c_int) {
+    // Safety: kill has no memory safety requirements.
+    unsafe {
+        libc::kill(-process_group, signal);
+    }
+}
+
+/// Block until the process exits, without reaping it.
+fn wait_for_exit(pid: u32) -> std::io::Result<()> {
+    loop {
+        // Safety: info is a valid siginfo_t, which waitid fills in.
+        let result = unsafe {
+            let mut info: libc::siginfo_t = std::mem::zeroed();
+            libc::waitid(
+                libc::P_PID,
+                pid as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        };
+        if result == 0 {
+            return Ok(());
+        }
+        let error = std::io::Error::last_os_error();
+        if error.kind() != std::io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+impl PythonConfig {
+    /// The process, which runs `cmd` with python3 of the virtualenv.
+    pub fn process_config(&self) -> ProcessConfig {
+        ProcessConfig {
+            program: "/bin/sh".to_owned(),
+            args: vec![
+                "-c".to_owned(),
+                format!(". '{}/bin/activate'; exec python3 {}", self.venv, self.cmd),
+            ],
+            ..Default::default()
+        }
+    }
+}
+
+impl ComponentConfig {
+    /// The process, which runs the component. None for built-in and remote components.
+    pub fn process_config(&self) -> Option<ProcessConfig> {
+        match (&self.process_config, &self.python_config) {
+            (Some(process_config), _) => Some(process_config.clone()),
+            (None, Some(python_config)) => Some(python_config.process_config()),
+            (None, None) => None,
+        }
+    }
+}
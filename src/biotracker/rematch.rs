@@ -0,0 +1,677 @@
+use super::{protocol::*, CommandLineArguments};
+use anyhow::{Context, Result};
+use pathfinding::{kuhn_munkres::kuhn_munkres, matrix::Matrix};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+
+/// Detections in consecutive frames are only linked into a tracklet, if the second closest
+/// candidate is at least this much further away than the closest one.
+const AMBIGUITY_RATIO: f64 = 1.5;
+/// Flow costs are in pixels, scaled before rounding to integers.
+const COST_SCALE: f64 = 100.0;
+
+/// Detection in a recorded track.
+#[derive(Clone, Copy, Debug)]
+struct Detection {
+    frame_number: u32,
+    /// Index of the feature in its frame
+    index: usize,
+    position: (f64, f64),
+    online_id: Option<u32>,
+}
+
+/// Detections which are safely linked from frame to frame.
+struct Tracklet {
+    detections: Vec<Detection>,
+}
+
+impl Tracklet {
+    fn start(&self) -> &Detection {
+        self.detections.first().unwrap()
+    }
+}
+
+/// Range of frames, in which detections of an entity received a different id than online.
+#[derive(Serialize, Debug)]
+pub struct IdChange {
+    pub first_frame: u32,
+    pub last_frame: u32,
+    pub online_id: Option<u32>,
+    pub id: Option<u32>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RematchReport {
+    pub track: String,
+    pub output: String,
+    pub detections: usize,
+    pub tracklets: usize,
+    pub identities: usize,
+    pub changed_detections: usize,
+    /// Detections which are not part of any path, they keep their online id
+    pub uncovered_detections: usize,
+    pub changes: Vec<IdChange>,
+}
+
+/// Re-match the identities of a recorded track over all frames at once, write the corrected track
+/// and a report of the changed ids.
+pub fn run(args: &CommandLineArguments) -> Result<()> {
+    let track_path = args.rematch.as_ref().context("No track to rematch")?;
+    let output = args.output.as_ref().context("No output path")?;
+    let file = std::fs::File::open(track_path)?;
+    let reader = std::io::BufReader::new(file);
+    let track: Track = serde_json::from_reader(reader)
+        .with_context(|| format!("Invalid track file {}", track_path.display()))?;
+
+    let (rematched, changes, summary) =
+        rematch_track(&track, args.rematch_max_gap, args.rematch_max_speed)?;
+    let file = std::fs::File::create(output)?;
+    let writer = std::io::BufWriter::new(file);
+    serde_json::to_writer(writer, &rematched)?;
+
+    let report = RematchReport {
+        track: track_path.to_string_lossy().to_string(),
+        output: output.to_string_lossy().to_string(),
+        detections: summary.detections,
+        tracklets: summary.tracklets,
+        identities: summary.identities,
+        changed_detections: summary.changed_detections,
+        uncovered_detections: summary.uncovered_detections,
+        changes,
+    };
+    eprintln!(
+        "{} detections in {} tracklets, {} identities, {} detections changed id in {} ranges, {} not covered",
+        report.detections,
+        report.tracklets,
+        report.identities,
+        report.changed_detections,
+        report.changes.len(),
+        report.uncovered_detections
+    );
+    if let Some(path) = args.report_path() {
+        let file = std::fs::File::create(&path)?;
+        let writer = std::io::BufWriter::new(file);
+        serde_json::to_writer_pretty(writer, &report)?;
+        eprintln!("Report written to {}", path.display());
+    }
+    Ok(())
+}
+
+struct Summary {
+    detections: usize,
+    tracklets: usize,
+    identities: usize,
+    changed_detections: usize,
+    uncovered_detections: usize,
+}
+
+fn rematch_track(
+    track: &Track,
+    max_gap: u32,
+    max_speed: f64,
+) -> Result<(Track, Vec<IdChange>, Summary)> {
+    let mut entity_ids = track
+        .features
+        .values()
+        .flat_map(|f| f.features.iter())
+        .filter_map(|f| f.id)
+        .collect::<Vec<_>>();
+    entity_ids.sort();
+    entity_ids.dedup();
+    if entity_ids.is_empty() {
+        return Err(anyhow::anyhow!("Track contains no entity ids"));
+    }
+
+    let center_index = track.skeleton.as_ref().map(|s| s.center_index as usize);
+    let mut frame_numbers = track.features.keys().copied().collect::<Vec<_>>();
+    frame_numbers.sort();
+    let frames = frame_numbers
+        .iter()
+        .map(|frame_number| {
+            track.features[frame_number]
+                .features
+                .iter()
+                .enumerate()
+                .filter_map(|(index, feature)| {
+                    Some(Detection {
+                        frame_number: *frame_number,
+                        index,
+                        position: position(feature, center_index)?,
+                        online_id: feature.id,
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let tracklets = link_tracklets(&frames, max_speed);
+    let paths = min_cost_paths(&tracklets, entity_ids.len(), max_gap, max_speed);
+
+    // Paths are labeled with the online ids they agree with the most
+    let mut agreement = Matrix::new(paths.len(), entity_ids.len(), 0i64);
+    for (path_idx, path) in paths.iter().enumerate() {
+        for detection in path.iter().flat_map(|t| tracklets[*t].detections.iter()) {
+            if let Some(id) = detection.online_id {
+                let id_idx = entity_ids.binary_search(&id).unwrap();
+                agreement[(path_idx, id_idx)] += 1;
+            }
+        }
+    }
+    let (_, labels) = kuhn_munkres(&agreement);
+    let mut ids: HashMap<(u32, usize), u32> = HashMap::new();
+    for (path_idx, path) in paths.iter().enumerate() {
+        for detection in path.iter().flat_map(|t| tracklets[*t].detections.iter()) {
+            let id = entity_ids[labels[path_idx]];
+            ids.insert((detection.frame_number, detection.index), id);
+        }
+    }
+
+    let mut rematched = track.clone();
+    let mut changes: Vec<IdChange> = vec![];
+    let mut detections = 0;
+    let mut changed_detections = 0;
+    let mut uncovered_detections = 0;
+    // Ids of the detections covered by paths, by online id, in the order of their frames
+    let mut id_history: HashMap<u32, Vec<(u32, u32)>> = HashMap::new();
+    for frame_number in frame_numbers.iter() {
+        let features = rematched.features.get_mut(frame_number).unwrap();
+        let path_ids = (0..features.features.len())
+            .filter_map(|index| ids.get(&(*frame_number, index)).copied())
+            .collect::<Vec<_>>();
+        for (index, feature) in features.features.iter_mut().enumerate() {
+            detections += 1;
+            let id = match ids.get(&(*frame_number, index)) {
+                Some(id) => {
+                    if let Some(online_id) = feature.id {
+                        let history = id_history.entry(online_id).or_default();
+                        history.push((*frame_number, *id));
+                    }
+                    Some(*id)
+                }
+                None => {
+                    // Detections without detected nodes, or which no path covers, keep their
+                    // online id, unless a path took it over in this frame
+                    uncovered_detections += 1;
+                    feature.id.filter(|id| !path_ids.contains(id))
+                }
+            };
+            if id == feature.id {
+                continue;
+            }
+            changed_detections += 1;
+            let online_id = feature.id;
+            feature.id = id;
+            let previous = changes.iter_mut().rev().find(|change| {
+                change.online_id == online_id
+                    && change.id == id
+                    && change.last_frame + 1 == *frame_number
+            });
+            match previous {
+                Some(change) => change.last_frame = *frame_number,
+                None => changes.push(IdChange {
+                    first_frame: *frame_number,
+                    last_frame: *frame_number,
+                    online_id,
+                    id,
+                }),
+            }
+        }
+    }
+
+    // Merged ids and entity events refer to entities by online id. They get the id of the last
+    // detection with that online id before their frame, or of the first one, if there is none
+    // before. A split is attributed to the merged entity, not to the detection it splits onto.
+    let remap = |online_id: u32, frame_number: u32| match id_history.get(&online_id) {
+        Some(history) => {
+            let idx = history.partition_point(|(f, _)| *f < frame_number);
+            history[idx.saturating_sub(1)].1
+        }
+        None => online_id,
+    };
+    for (frame_number, features) in rematched.features.iter_mut() {
+        let frame_ids = features
+            .features
+            .iter()
+            .filter_map(|f| f.id)
+            .collect::<Vec<_>>();
+        for feature in features.features.iter_mut() {
+            // Entities with a detection of their own are not merged into another one
+            feature.merged_ids = match feature.id {
+                Some(_) => feature
+                    .merged_ids
+                    .iter()
+                    .map(|id| remap(*id, *frame_number))
+                    .filter(|id| !frame_ids.contains(id))
+                    .collect(),
+                None => vec![],
+            };
+        }
+    }
+    for event in rematched.entity_events.iter_mut() {
+        event.id = remap(event.id, event.frame_number);
+    }
+
+    let summary = Summary {
+        detections,
+        tracklets: tracklets.len(),
+        identities: paths.len(),
+        changed_detections,
+        uncovered_detections,
+    };
+    Ok((rematched, changes, summary))
+}
+
+/// Position of the center node, or the mean of all detected nodes if it is missing.
+fn position(feature: &Feature, center_index: Option<usize>) -> Option<(f64, f64)> {
+    let is_detected = |node: &&SkeletonNode| !node.x.is_nan() && !node.y.is_nan();
+    if let Some(center) = center_index.and_then(|i| feature.image_nodes.get(i)) {
+        if is_detected(&center) {
+            return Some((center.x as f64, center.y as f64));
+        }
+    }
+    let nodes = feature
+        .image_nodes
+        .iter()
+        .filter(is_detected)
+        .collect::<Vec<_>>();
+    if nodes.is_empty() {
+        return None;
+    }
+    let n = nodes.len() as f64;
+    let x = nodes.iter().map(|node| node.x as f64).sum::<f64>() / n;
+    let y = nodes.iter().map(|node| node.y as f64).sum::<f64>() / n;
+    Some((x, y))
+}
+
+fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+}
+
+/// Link detections of consecutive frames, which are mutually closest and not ambiguous.
+fn link_tracklets(frames: &[Vec<Detection>], max_speed: f64) -> Vec<Tracklet> {
+    // Closest detection in `to`, if it is within reach and clearly closer than the others
+    let closest = |detection: &Detection, to: &[Detection]| -> Option<usize> {
+        let mut distances = to
+            .iter()
+            .enumerate()
+            .map(|(idx, other)| (distance(detection.position, other.position), idx))
+            .collect::<Vec<_>>();
+        distances.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let (best, idx) = *distances.first()?;
+        let unambiguous = match distances.get(1) {
+            Some((second, _)) => *second >= AMBIGUITY_RATIO * best && *second > 0.0,
+            None => true,
+        };
+        (best <= max_speed && unambiguous).then_some(idx)
+    };
+
+    let mut tracklets: Vec<Tracklet> = vec![];
+    // Tracklet of each detection of the previous frame
+    let mut open: Vec<usize> = vec![];
+    for (frame_idx, detections) in frames.iter().enumerate() {
+        let previous = match frame_idx {
+            0 => None,
+            _ => Some(&frames[frame_idx - 1]),
+        };
+        let mut current_open = vec![];
+        for (idx, detection) in detections.iter().enumerate() {
+            let link = previous
+                .filter(|p| {
+                    p.first()
+                        .is_some_and(|d| d.frame_number + 1 == detection.frame_number)
+                })
+                .and_then(|previous| {
+                    let previous_idx = closest(detection, previous)?;
+                    (closest(&previous[previous_idx], detections) == Some(idx))
+                        .then_some(previous_idx)
+                });
+            match link {
+                Some(previous_idx) => {
+                    let tracklet_idx = open[previous_idx];
+                    tracklets[tracklet_idx].detections.push(*detection);
+                    current_open.push(tracklet_idx);
+                }
+                None => {
+                    tracklets.push(Tracklet {
+                        detections: vec![*detection],
+                    });
+                    current_open.push(tracklets.len() - 1);
+                }
+            }
+        }
+        open = current_open;
+    }
+    tracklets
+}
+
+struct Edge {
+    to: usize,
+    capacity: i64,
+    cost: i64,
+    /// Index of the reverse edge in the adjacency list of `to`
+    reverse: usize,
+    /// Residual edges, which allow to undo flow
+    is_reverse: bool,
+}
+
+/// Directed graph for successive shortest path min-cost flow.
+struct FlowGraph {
+    edges: Vec<Vec<Edge>>,
+}
+
+impl FlowGraph {
+    fn new(node_count: usize) -> Self {
+        Self {
+            edges: (0..node_count).map(|_| vec![]).collect(),
+        }
+    }
+
+    fn add_edge(&mut self, from: usize, to: usize, cost: i64) {
+        let reverse = self.edges[to].len();
+        let forward = self.edges[from].len();
+        self.edges[from].push(Edge {
+            to,
+            capacity: 1,
+            cost,
+            reverse,
+            is_reverse: false,
+        });
+        self.edges[to].push(Edge {
+            to: from,
+            capacity: 0,
+            cost: -cost,
+            reverse: forward,
+            is_reverse: true,
+        });
+    }
+
+    /// Send one unit of flow along the cheapest path, if it has negative cost. Costs may be
+    /// negative, so the path is found with the Bellman-Ford queue algorithm.
+    fn augment(&mut self, source: usize, sink: usize) -> bool {
+        let n = self.edges.len();
+        let mut cost = vec![i64::MAX; n];
+        let mut previous: Vec<Option<(usize, usize)>> = vec![None; n];
+        let mut queued = vec![false; n];
+        let mut queue = VecDeque::from([source]);
+        cost[source] = 0;
+        while let Some(node) = queue.pop_front() {
+            queued[node] = false;
+            for (edge_idx, edge) in self.edges[node].iter().enumerate() {
+                if edge.capacity > 0 && cost[node] + edge.cost < cost[edge.to] {
+                    cost[edge.to] = cost[node] + edge.cost;
+                    previous[edge.to] = Some((node, edge_idx));
+                    if !queued[edge.to] {
+                        queued[edge.to] = true;
+                        queue.push_back(edge.to);
+                    }
+                }
+            }
+        }
+        if cost[sink] >= 0 {
+            return false;
+        }
+        let mut node = sink;
+        while let Some((from, edge_idx)) = previous[node] {
+            let reverse = self.edges[from][edge_idx].reverse;
+            self.edges[from][edge_idx].capacity -= 1;
+            self.edges[node][reverse].capacity += 1;
+            node = from;
+        }
+        true
+    }
+}
+
+/// Cover the tracklets with up to `identity_count` disjoint paths through time. Every covered
+/// detection is rewarded, transitions cost their distance plus `max_speed` per missing frame.
+/// Returns the tracklet indices of each path.
+fn min_cost_paths(
+    tracklets: &[Tracklet],
+    identity_count: usize,
+    max_gap: u32,
+    max_speed: f64,
+) -> Vec<Vec<usize>> {
+    // Covering a detection is worth more than the most expensive transition, so that as many
+    // detections as possible are covered.
+    let detection_reward = 2.0 * max_speed * max_gap.max(1) as f64;
+    let source = 0;
+    let sink = 1;
+    let node_in = |tracklet_idx: usize| 2 + 2 * tracklet_idx;
+    let node_out = |tracklet_idx: usize| 3 + 2 * tracklet_idx;
+    let mut graph = FlowGraph::new(2 + 2 * tracklets.len());
+    for (idx, tracklet) in tracklets.iter().enumerate() {
+        let reward = detection_reward * tracklet.detections.len() as f64;
+        graph.add_edge(source, node_in(idx), 0);
+        graph.add_edge(node_in(idx), node_out(idx), -(reward * COST_SCALE) as i64);
+        graph.add_edge(node_out(idx), sink, 0);
+        // Tracklets are created in frame order, so they are sorted by their first frame
+        let end = tracklet.detections.last().unwrap();
+        let first_next = tracklets.partition_point(|t| t.start().frame_number <= end.frame_number);
+        for (next_idx, next) in tracklets.iter().enumerate().skip(first_next) {
+            let start = next.start();
+            if start.frame_number - end.frame_number > max_gap {
+                break;
+            }
+            let gap = (start.frame_number - end.frame_number) as f64;
+            let distance = distance(end.position, start.position);
+            if distance > max_speed * gap {
+                continue;
+            }
+            let cost = distance + max_speed * (gap - 1.0);
+            graph.add_edge(node_out(idx), node_in(next_idx), (cost * COST_SCALE) as i64);
+        }
+    }
+    for _ in 0..identity_count {
+        if !graph.augment(source, sink) {
+            break;
+        }
+    }
+
+    // Follow the edges carrying flow from the source to the sink
+    let carries_flow = |edge: &&Edge| !edge.is_reverse && edge.capacity == 0;
+    let mut paths = vec![];
+    for edge in graph.edges[source].iter().filter(carries_flow) {
+        let mut path = vec![];
+        let mut node = edge.to;
+        while node != sink {
+            if node % 2 == 0 {
+                path.push((node - 2) / 2);
+            }
+            node = graph.edges[node].iter().find(carries_flow).unwrap().to;
+        }
+        paths.push(path);
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detection(frame_number: u32, index: usize, x: f64) -> Detection {
+        Detection {
+            frame_number,
+            index,
+            position: (x, 0.0),
+            online_id: None,
+        }
+    }
+
+    /// Tracklet moving one pixel per frame, starting at `x`.
+    fn tracklet(frame_numbers: std::ops::Range<u32>, x: f64) -> Tracklet {
+        let start = frame_numbers.start;
+        Tracklet {
+            detections: frame_numbers
+                .map(|frame_number| detection(frame_number, 0, x + (frame_number - start) as f64))
+                .collect(),
+        }
+    }
+
+    fn feature(x: f32, id: u32, merged_ids: Vec<u32>) -> Feature {
+        Feature {
+            image_nodes: vec![SkeletonNode {
+                x,
+                y: 0.0,
+                score: 1.0,
+            }],
+            id: Some(id),
+            merged_ids,
+            ..Default::default()
+        }
+    }
+
+    fn track(frames: Vec<Vec<Feature>>) -> Track {
+        Track {
+            features: frames
+                .into_iter()
+                .enumerate()
+                .map(|(frame_number, features)| {
+                    let features = Features {
+                        features,
+                        frame_number: frame_number as u32,
+                        ..Default::default()
+                    };
+                    (frame_number as u32, features)
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn links_separated_detections() {
+        let frames = (0..3)
+            .map(|f| vec![detection(f, 0, f as f64), detection(f, 1, 100.0 + f as f64)])
+            .collect::<Vec<_>>();
+        let tracklets = link_tracklets(&frames, 20.0);
+        assert_eq!(tracklets.len(), 2);
+        for (idx, tracklet) in tracklets.iter().enumerate() {
+            assert_eq!(tracklet.detections.len(), 3);
+            assert!(tracklet.detections.iter().all(|d| d.index == idx));
+        }
+    }
+
+    #[test]
+    fn does_not_link_ambiguous_detections() {
+        let frames = vec![
+            vec![detection(0, 0, 0.0)],
+            vec![detection(1, 0, 5.0), detection(1, 1, -6.0)],
+        ];
+        let tracklets = link_tracklets(&frames, 20.0);
+        assert_eq!(tracklets.len(), 3);
+    }
+
+    #[test]
+    fn does_not_link_across_missing_frames() {
+        let frames = vec![vec![detection(0, 0, 0.0)], vec![detection(2, 0, 1.0)]];
+        assert_eq!(link_tracklets(&frames, 20.0).len(), 2);
+    }
+
+    #[test]
+    fn does_not_link_beyond_max_speed() {
+        let frames = vec![vec![detection(0, 0, 0.0)], vec![detection(1, 0, 30.0)]];
+        assert_eq!(link_tracklets(&frames, 20.0).len(), 2);
+    }
+
+    #[test]
+    fn joins_tracklets_across_gaps() {
+        let tracklets = vec![tracklet(0..5, 0.0), tracklet(8..12, 8.0)];
+        assert_eq!(min_cost_paths(&tracklets, 1, 5, 20.0), vec![vec![0, 1]]);
+    }
+
+    #[test]
+    fn does_not_join_tracklets_beyond_max_gap() {
+        let tracklets = vec![tracklet(0..5, 0.0), tracklet(20..24, 8.0)];
+        assert_eq!(
+            min_cost_paths(&tracklets, 2, 5, 20.0),
+            vec![vec![0], vec![1]]
+        );
+    }
+
+    #[test]
+    fn does_not_join_distant_tracklets() {
+        let tracklets = vec![tracklet(0..5, 0.0), tracklet(6..10, 100.0)];
+        assert_eq!(
+            min_cost_paths(&tracklets, 2, 5, 20.0),
+            vec![vec![0], vec![1]]
+        );
+    }
+
+    #[test]
+    fn covers_as_many_detections_as_possible() {
+        let tracklets = vec![tracklet(0..3, 100.0), tracklet(0..10, 0.0)];
+        assert_eq!(min_cost_paths(&tracklets, 1, 5, 20.0), vec![vec![1]]);
+    }
+
+    #[test]
+    fn uncovered_detections_keep_their_id() {
+        let mut frames = (0..5)
+            .map(|f| vec![feature(f as f32, 1, vec![])])
+            .collect::<Vec<_>>();
+        frames[2].push(feature(f32::NAN, 2, vec![]));
+        let (rematched, changes, summary) = rematch_track(&track(frames), 5, 20.0).unwrap();
+        assert_eq!(rematched.features[&2].features[1].id, Some(2));
+        assert_eq!(summary.uncovered_detections, 1);
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn uncovered_detections_lose_ids_taken_by_paths() {
+        let mut frames = (0..5)
+            .map(|f| {
+                vec![
+                    feature(f as f32, 1, vec![]),
+                    feature(100.0 + f as f32, 2, vec![]),
+                ]
+            })
+            .collect::<Vec<_>>();
+        frames[2].push(feature(f32::NAN, 1, vec![2]));
+        frames[3][0].merged_ids = vec![2];
+        let (rematched, changes, summary) = rematch_track(&track(frames), 5, 20.0).unwrap();
+        let undetected = &rematched.features[&2].features[2];
+        assert_eq!(undetected.id, None);
+        assert!(undetected.merged_ids.is_empty());
+        assert!(rematched.features[&3].features[0].merged_ids.is_empty());
+        assert_eq!(summary.uncovered_detections, 1);
+        assert_eq!(changes.len(), 1);
+    }
+
+    #[test]
+    fn remaps_merged_ids_and_entity_events() {
+        // Entity 2 merges into entity 1 in frame 3, the online ids are swapped after the split
+        let frames = (0..10)
+            .map(|f| match f {
+                0..=2 => vec![
+                    feature(f as f32, 1, vec![]),
+                    feature(100.0 + f as f32, 2, vec![]),
+                ],
+                3 => vec![feature(f as f32, 1, vec![2])],
+                _ => vec![
+                    feature(f as f32, 2, vec![]),
+                    feature(100.0 + f as f32, 1, vec![]),
+                ],
+            })
+            .collect::<Vec<_>>();
+        let mut recorded = track(frames);
+        let event = |frame_number, event_type: EntityEventType| EntityEvent {
+            frame_number,
+            id: 2,
+            event_type: event_type as i32,
+        };
+        recorded.entity_events = vec![
+            event(0, EntityEventType::Birth),
+            event(3, EntityEventType::Merge),
+            event(4, EntityEventType::Split),
+        ];
+        let (rematched, _, _) = rematch_track(&recorded, 5, 20.0).unwrap();
+        let merged = rematched.features[&9].features[1].id.unwrap();
+        assert_eq!(rematched.features[&0].features[1].id, Some(merged));
+        assert_ne!(merged, 2);
+        assert_eq!(rematched.features[&3].features[0].merged_ids, vec![merged]);
+        let events = rematched
+            .entity_events
+            .iter()
+            .map(|e| (e.frame_number, e.id))
+            .collect::<Vec<_>>();
+        assert_eq!(events, vec![(0, merged), (3, merged), (4, merged)]);
+    }
+}
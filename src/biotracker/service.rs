@@ -1,9 +1,11 @@
 use super::{
     bio_tracker_server::BioTracker,
-    protocol::{Command, Empty, Experiment, Image},
+    protocol::{Command, Empty, Experiment, Image, SubscribeRequest},
+    subscription::{StatePublisher, StateUpdateStream},
     BioTrackerCommand, ChannelRequest,
 };
 use anyhow::Result;
+use std::sync::Arc;
 use tokio::sync::mpsc::Sender;
 use tonic::{Request, Response, Status};
 
@@ -11,6 +13,7 @@ pub struct Service {
     pub command_tx: Sender<ChannelRequest<Command, Result<Empty>>>,
     pub state_tx: Sender<ChannelRequest<(), Experiment>>,
     pub image_tx: Sender<ChannelRequest<Image, Result<Empty>>>,
+    pub publisher: Arc<StatePublisher>,
 }
 
 #[tonic::async_trait]
@@ -23,6 +26,17 @@ impl BioTracker for Service {
         ))
     }
 
+    type subscribeStream = StateUpdateStream;
+
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> Result<Response<Self::subscribeStream>, Status> {
+        Ok(Response::new(
+            self.publisher.subscribe(request.into_inner()),
+        ))
+    }
+
     async fn command(
         &self,
         request: Request<BioTrackerCommand>,
@@ -14,6 +14,7 @@ impl SharedImage {
 
 pub struct DoubleBuffer {
     data: VecDeque<SharedImage>,
+    capacity: usize,
 }
 
 pub struct SharedImage {
@@ -23,13 +24,22 @@ pub struct SharedImage {
 
 impl DoubleBuffer {
     pub fn new() -> Self {
-        Self { data: [].into() }
+        Self::with_capacity(2)
+    }
+
+    /// Buffers are reused in FIFO order, an image stays valid until `capacity` newer images were
+    /// requested.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            data: [].into(),
+            capacity: capacity.max(2),
+        }
     }
 
     pub fn get_mut(&mut self, width: u32, height: u32, channels: u32) -> Result<&mut SharedImage> {
         let mut shared_image = None;
         let len = (width * height * channels) as usize;
-        if self.data.len() >= 2 {
+        if self.data.len() >= self.capacity {
             let image = self.data.pop_front().unwrap();
             assert!(image.shmem.is_owner());
             if image.shmem.len() == len {
@@ -56,7 +66,7 @@ impl DoubleBuffer {
     }
 
     pub fn get(&mut self, image: &Image) -> Result<&SharedImage> {
-        let shared_image = if self.data.len() >= 2 {
+        let shared_image = if self.data.len() >= self.capacity {
             let mut shared_image = self.data.pop_front().unwrap();
             if image.shm_id != shared_image.id() {
                 shared_image = SharedImage::try_from(image)?;
@@ -0,0 +1,416 @@
+use super::{blob_detector::skeleton, decoder::VideoSampler, protocol::*};
+use anyhow::{Context, Result};
+use cv::{
+    core::{Point, Point2f, Scalar, Vector},
+    prelude::*,
+    types::VectorOfPoint2f,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+const BACKGROUND_INTENSITY: f64 = 200.0;
+const ANIMAL_INTENSITY: f64 = 40.0;
+/// Number of points of the polygon approximating the outline of an animal
+const OUTLINE_POINTS: usize = 24;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MotionModel {
+    /// Correlated random walk
+    RandomWalk,
+    /// Animals align with and move towards their neighbours, but keep a distance
+    Schooling,
+    /// Animals move counter-clockwise along the walls of the arena
+    WallFollowing,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulatorConfig {
+    animals: u32,
+    motion: MotionModel,
+    /// The same seed always yields the same video
+    seed: u64,
+    frames: u32,
+    /// Image size in pixels
+    width: u32,
+    height: u32,
+    /// Mean speed of the animals, in cm per frame
+    speed: f64,
+    /// Standard deviation of the change of heading between frames, in radians
+    turning: f64,
+    /// Size of the animals, in cm
+    body_length: f64,
+    body_width: f64,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        Self {
+            animals: 4,
+            motion: MotionModel::RandomWalk,
+            seed: 0,
+            frames: 1000,
+            width: 2048,
+            height: 2048,
+            speed: 0.5,
+            turning: 0.2,
+            body_length: 5.0,
+            body_width: 1.5,
+        }
+    }
+}
+
+impl SimulatorConfig {
+    /// Reads the settings from the query of the uri, e.g. `fake:///?animals=8&motion=schooling`.
+    pub fn from_uri(uri: &str) -> Result<Self> {
+        let query = uri.split_once('?').map(|(_, query)| query).unwrap_or("");
+        let mut settings = serde_json::Map::new();
+        for setting in query.split('&').filter(|s| !s.is_empty()) {
+            let (key, value) = setting
+                .split_once('=')
+                .with_context(|| format!("Invalid simulator setting '{}'", setting))?;
+            // Values which are no valid JSON, e.g. names of motion models, are strings
+            let value = serde_json::from_str(value)
+                .unwrap_or_else(|_| serde_json::Value::String(value.to_owned()));
+            settings.insert(key.to_owned(), value);
+        }
+        serde_json::from_value(serde_json::Value::Object(settings))
+            .context("Invalid simulator settings")
+    }
+}
+
+/// Position in arena coordinates, in cm, and heading in radians.
+#[derive(Clone, Copy, Debug)]
+struct AnimalState {
+    x: f64,
+    y: f64,
+    heading: f64,
+}
+
+/// Video source rendering simulated animals as ellipses. All frames are simulated when the
+/// video is opened, so that seeking is exact and the ground truth is known.
+pub struct Simulator {
+    config: SimulatorConfig,
+    /// States of all animals in every frame
+    frames: Vec<Vec<AnimalState>>,
+    /// Transforms arena coordinates to pixels
+    projection: Projection,
+    position: usize,
+}
+
+impl Simulator {
+    pub fn new(config: SimulatorConfig, arena: &Option<Arena>) -> Result<Self> {
+        if config.width == 0 || config.height == 0 {
+            return Err(anyhow::anyhow!("Invalid video size"));
+        }
+        let projection = Projection::new(arena, config.width, config.height)?;
+        let frames = simulate(&config, projection.half_width, projection.half_height);
+        Ok(Self {
+            config,
+            frames,
+            projection,
+            position: 0,
+        })
+    }
+
+    pub fn video_info(&self, uri: String, fps: f64) -> VideoInfo {
+        VideoInfo {
+            path: uri,
+            fps,
+            width: self.config.width,
+            height: self.config.height,
+            frame_count: self.config.frames,
+        }
+    }
+
+    fn features(&self, frame_number: usize) -> Result<Features> {
+        let mut features = vec![];
+        for (animal_idx, state) in self.frames[frame_number].iter().enumerate() {
+            let world = [
+                self.body_point(state, self.config.body_length / 2.0, 0.0),
+                (state.x, state.y),
+            ];
+            let image = self.projection.world_to_image(&world)?;
+            let node = |(x, y): (f32, f32)| SkeletonNode { x, y, score: 1.0 };
+            features.push(Feature {
+                image_nodes: image.iter().map(|p| node((p.x, p.y))).collect(),
+                world_nodes: world
+                    .iter()
+                    .map(|(x, y)| node((*x as f32, *y as f32)))
+                    .collect(),
+                score: 1.0,
+                // Entity ids start at 1
+                id: Some(animal_idx as u32 + 1),
+                ..Default::default()
+            });
+        }
+        Ok(Features {
+            features,
+            frame_number: frame_number as u32,
+            ..Default::default()
+        })
+    }
+
+    /// Point at `forward` cm along the heading and `left` cm to the left of the animal.
+    fn body_point(&self, state: &AnimalState, forward: f64, left: f64) -> (f64, f64) {
+        let (sin, cos) = state.heading.sin_cos();
+        (
+            state.x + forward * cos - left * sin,
+            state.y + forward * sin + left * cos,
+        )
+    }
+}
+
+impl VideoSampler for Simulator {
+    fn get_image(&mut self, mat: &mut Mat) -> Result<()> {
+        let states = self
+            .frames
+            .get(self.position)
+            .context("End of simulation")?;
+        mat.set_to(&Scalar::all(BACKGROUND_INTENSITY), &Mat::default())?;
+        for state in states.iter() {
+            let outline = (0..OUTLINE_POINTS)
+                .map(|i| {
+                    let angle = 2.0 * PI * i as f64 / OUTLINE_POINTS as f64;
+                    self.body_point(
+                        state,
+                        self.config.body_length / 2.0 * angle.cos(),
+                        self.config.body_width / 2.0 * angle.sin(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            let outline = self
+                .projection
+                .world_to_image(&outline)?
+                .iter()
+                .map(|p| Point::new(p.x.round() as i32, p.y.round() as i32))
+                .collect::<Vector<Point>>();
+            let polygons = Vector::<Vector<Point>>::from_iter([outline]);
+            cv::imgproc::fill_poly(
+                mat,
+                &polygons,
+                Scalar::all(ANIMAL_INTENSITY),
+                cv::imgproc::LINE_AA,
+                0,
+                Point::default(),
+            )?;
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn seek(&mut self, target_framenumber: u32) -> Result<()> {
+        if target_framenumber > self.config.frames {
+            return Err(anyhow::anyhow!(
+                "Frame {} is beyond the end of the simulation",
+                target_framenumber
+            ));
+        }
+        self.position = target_framenumber as usize;
+        Ok(())
+    }
+
+    fn ground_truth(&self, track_start: u32) -> Result<Option<Track>> {
+        let mut features = HashMap::new();
+        for frame_number in track_start as usize..self.frames.len() {
+            let mut frame_features = self.features(frame_number)?;
+            // Frames are numbered like the recorded track
+            frame_features.frame_number -= track_start;
+            features.insert(frame_features.frame_number, frame_features);
+        }
+        Ok(Some(Track {
+            skeleton: Some(skeleton()),
+            original_frame_count: self.config.frames,
+            original_track_start: track_start,
+            features,
+            ..Default::default()
+        }))
+    }
+}
+
+/// Maps arena coordinates to pixels, with the inverse of the rectification of the arena.
+struct Projection {
+    homography: Option<Mat>,
+    width: u32,
+    height: u32,
+    half_width: f64,
+    half_height: f64,
+}
+
+impl Projection {
+    fn new(arena: &Option<Arena>, width: u32, height: u32) -> Result<Self> {
+        let (half_width, half_height) = match arena {
+            Some(arena) if arena.width_cm > 0 && arena.height_cm > 0 => {
+                (arena.width_cm as f64 / 2.0, arena.height_cm as f64 / 2.0)
+            }
+            _ => (50.0, 50.0),
+        };
+        // Without rectification corners, the arena fills the image
+        let homography = match arena {
+            Some(arena) if arena.rectification_corners.len() == 4 => {
+                let image_to_world = arena.rectification_transform(width, height)?;
+                let mut world_to_image = Mat::default();
+                cv::core::invert(&image_to_world, &mut world_to_image, cv::core::DECOMP_LU)?;
+                Some(world_to_image)
+            }
+            _ => None,
+        };
+        Ok(Self {
+            homography,
+            width,
+            height,
+            half_width,
+            half_height,
+        })
+    }
+
+    fn world_to_image(&self, points: &[(f64, f64)]) -> Result<Vec<Point2f>> {
+        let homography = match &self.homography {
+            Some(homography) => homography,
+            None => {
+                // Arena coordinates point up, image coordinates down
+                let scale_x = self.width as f64 / (2.0 * self.half_width);
+                let scale_y = self.height as f64 / (2.0 * self.half_height);
+                return Ok(points
+                    .iter()
+                    .map(|(x, y)| {
+                        Point2f::new(
+                            ((x + self.half_width) * scale_x) as f32,
+                            ((self.half_height - y) * scale_y) as f32,
+                        )
+                    })
+                    .collect());
+            }
+        };
+        let world = points
+            .iter()
+            .map(|(x, y)| Point2f::new(*x as f32, *y as f32))
+            .collect::<VectorOfPoint2f>();
+        let mut image = VectorOfPoint2f::new();
+        cv::core::perspective_transform(&world, &mut image, homography)?;
+        Ok(image.to_vec())
+    }
+}
+
+/// Simulates all frames. Animals stay within the arena, one body length away from the walls.
+fn simulate(config: &SimulatorConfig, half_width: f64, half_height: f64) -> Vec<Vec<AnimalState>> {
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let margin_x = config.body_length.min(half_width / 2.0);
+    let margin_y = config.body_length.min(half_height / 2.0);
+    let (max_x, max_y) = (half_width - margin_x, half_height - margin_y);
+    let mut states = (0..config.animals)
+        .map(|_| AnimalState {
+            x: rng.gen_range(-max_x..=max_x),
+            y: rng.gen_range(-max_y..=max_y),
+            heading: rng.gen_range(-PI..PI),
+        })
+        .collect::<Vec<_>>();
+    let mut frames = Vec::with_capacity(config.frames as usize);
+    for _ in 0..config.frames {
+        frames.push(states.clone());
+        let previous = states.clone();
+        for (animal_idx, state) in states.iter_mut().enumerate() {
+            let mut heading = state.heading + normal(&mut rng) * config.turning;
+            let target = match config.motion {
+                MotionModel::RandomWalk => None,
+                MotionModel::Schooling => schooling_heading(&previous, animal_idx, config),
+                MotionModel::WallFollowing => Some(wall_following_heading(
+                    state,
+                    half_width,
+                    half_height,
+                    config.body_length,
+                )),
+            };
+            if let Some(target) = target {
+                heading += 0.2 * angle_difference(target, heading);
+            }
+
+            // Animals turn away from walls ahead
+            let speed = config.speed * (1.0 + 0.2 * normal(&mut rng)).max(0.0);
+            let (sin, cos) = heading.sin_cos();
+            if (state.x + speed * cos).abs() > max_x {
+                heading = PI - heading;
+            }
+            if (state.y + speed * sin).abs() > max_y {
+                heading = -heading;
+            }
+            let (sin, cos) = heading.sin_cos();
+            state.x = (state.x + speed * cos).clamp(-max_x, max_x);
+            state.y = (state.y + speed * sin).clamp(-max_y, max_y);
+            state.heading = angle_difference(heading, 0.0);
+        }
+    }
+    frames
+}
+
+/// Heading towards the mean heading and position of the neighbours, and away from neighbours
+/// closer than two body lengths.
+fn schooling_heading(
+    states: &[AnimalState],
+    animal_idx: usize,
+    config: &SimulatorConfig,
+) -> Option<f64> {
+    let state = &states[animal_idx];
+    let (mut x, mut y) = (0.0, 0.0);
+    for (other_idx, other) in states.iter().enumerate() {
+        let (dx, dy) = (other.x - state.x, other.y - state.y);
+        let distance = (dx * dx + dy * dy).sqrt();
+        if other_idx == animal_idx || distance == 0.0 || distance > 10.0 * config.body_length {
+            continue;
+        }
+        if distance < 2.0 * config.body_length {
+            x -= 2.0 * dx / distance;
+            y -= 2.0 * dy / distance;
+        } else {
+            x += other.heading.cos() + 0.5 * dx / distance;
+            y += other.heading.sin() + 0.5 * dy / distance;
+        }
+    }
+    match x != 0.0 || y != 0.0 {
+        true => Some(y.atan2(x)),
+        false => None,
+    }
+}
+
+/// Heading along the closest wall, counter-clockwise, turning towards a distance of two body
+/// lengths to the wall.
+fn wall_following_heading(
+    state: &AnimalState,
+    half_width: f64,
+    half_height: f64,
+    body_length: f64,
+) -> f64 {
+    // Distance to the wall and direction along the wall, which is on the right hand side
+    let walls = [
+        (state.y + half_height, 0.0),
+        (half_width - state.x, PI / 2.0),
+        (half_height - state.y, PI),
+        (state.x + half_width, -PI / 2.0),
+    ];
+    let (distance, along_wall) = walls
+        .into_iter()
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+        .unwrap();
+    let preferred_distance = 2.0 * body_length;
+    let towards_wall = ((distance - preferred_distance) / preferred_distance).clamp(-1.0, 1.0);
+    along_wall - towards_wall * PI / 4.0
+}
+
+/// Difference between two angles, between -π and π.
+fn angle_difference(a: f64, b: f64) -> f64 {
+    let difference = (a - b).rem_euclid(2.0 * PI);
+    match difference > PI {
+        true => difference - 2.0 * PI,
+        false => difference,
+    }
+}
+
+/// Standard normal distributed sample (Box-Muller transform).
+fn normal(rng: &mut StdRng) -> f64 {
+    let u1: f64 = rng.gen_range(f64::EPSILON..1.0);
+    let u2: f64 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+}
@@ -0,0 +1,446 @@
+use super::{kalman::KalmanFilter, matcher::Matcher, protocol::*};
+use pathfinding::{kuhn_munkres::kuhn_munkres_min, matrix::Matrix};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tonic::{Request, Response, Status};
+
+/// IoU costs are scaled before rounding to integers. A cost of `IOU_SCALE` means no overlap.
+const IOU_SCALE: f64 = 1000.0;
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SortMatcherConfig {
+    /// Features with at least this score are associated first
+    high_threshold: f32,
+    /// Features with a lower score are ignored, the others are associated with the remaining
+    /// tracks
+    low_threshold: f32,
+    /// Unassociated features with at least this score start new tracks
+    new_track_threshold: f32,
+    /// Minimum IoU of associated high and low confidence features
+    high_iou_threshold: f64,
+    low_iou_threshold: f64,
+    /// Tracks which are not associated for this many frames are removed
+    max_age: u32,
+    /// Tracks receive an entity id after being associated in this many frames
+    min_hits: u32,
+    /// Bounding boxes of the nodes are enlarged to at least this width and height, in pixels
+    min_box_size: f64,
+    /// Variance of the acceleration of box centers and sizes, in (pixels/frame²)²
+    process_noise: f64,
+    /// Variance of measured box centers and sizes, in pixels²
+    measurement_noise: f64,
+}
+
+impl Default for SortMatcherConfig {
+    fn default() -> Self {
+        Self {
+            high_threshold: 0.5,
+            low_threshold: 0.1,
+            new_track_threshold: 0.6,
+            high_iou_threshold: 0.2,
+            low_iou_threshold: 0.5,
+            max_age: 30,
+            min_hits: 3,
+            min_box_size: 10.0,
+            process_noise: 1.0,
+            measurement_noise: 4.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct BoundingBox {
+    x1: f64,
+    y1: f64,
+    x2: f64,
+    y2: f64,
+}
+
+/// Bounding box following a feature, with constant velocity motion of its center and size.
+struct Track {
+    center: KalmanFilter,
+    size: KalmanFilter,
+    frame_number: u32,
+    /// Number of frames the track was associated in
+    hits: u32,
+    entity_id: Option<u32>,
+}
+
+#[derive(Default)]
+struct SortMatcherState {
+    config: SortMatcherConfig,
+    tracks: Vec<Track>,
+    /// Last position of entities which are not held by a track, used to hand their id to a
+    /// nearby new track.
+    released: HashMap<u32, (f64, f64)>,
+}
+
+/// Built-in matcher which associates bounding boxes of features in two stages, first the
+/// confident features, then the remaining ones (SORT with ByteTrack association).
+#[derive(Default)]
+pub struct SortMatcherService {
+    inner: Arc<Mutex<SortMatcherState>>,
+}
+
+#[tonic::async_trait]
+impl Matcher for SortMatcherService {
+    async fn match_features(
+        &self,
+        request: Request<MatcherRequest>,
+    ) -> Result<Response<Features>, Status> {
+        let MatcherRequest {
+            features,
+            entity_ids,
+            ..
+        } = request.into_inner();
+        let features = match features {
+            Some(f) => f,
+            None => {
+                return Err(Status::invalid_argument(
+                    "Received MatcherRequest without features",
+                ))
+            }
+        };
+        let mut state = self.inner.lock().unwrap();
+        Ok(Response::new(state.update(entity_ids, features)))
+    }
+
+    async fn switch_ids(
+        &self,
+        request: Request<EntityIdSwitch>,
+    ) -> Result<Response<Empty>, Status> {
+        let EntityIdSwitch { id1, id2 } = request.into_inner();
+        let mut state = self.inner.lock().unwrap();
+        for track in state.tracks.iter_mut() {
+            if track.entity_id == Some(id1) {
+                track.entity_id = Some(id2);
+            } else if track.entity_id == Some(id2) {
+                track.entity_id = Some(id1);
+            }
+        }
+        let position1 = state.released.remove(&id1);
+        let position2 = state.released.remove(&id2);
+        if let Some(position) = position1 {
+            state.released.insert(id2, position);
+        }
+        if let Some(position) = position2 {
+            state.released.insert(id1, position);
+        }
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn heartbeat(&self, _: Request<Empty>) -> Result<Response<Empty>, Status> {
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn reset(&self, _: Request<Empty>) -> Result<Response<Empty>, Status> {
+        let mut state = self.inner.lock().unwrap();
+        state.tracks.clear();
+        state.released.clear();
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn seed(&self, request: Request<Features>) -> Result<Response<Empty>, Status> {
+        let features = request.into_inner();
+        let mut state = self.inner.lock().unwrap();
+        state.seed(features);
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn set_config(
+        &self,
+        request: Request<ComponentConfig>,
+    ) -> Result<Response<Empty>, Status> {
+        let config = request.into_inner().config_json;
+        let config: SortMatcherConfig = serde_json::from_str(&config).map_err(|e| {
+            Status::invalid_argument(format!("Could not parse config: {}", e.to_string()))
+        })?;
+        let noise_valid = config.measurement_noise > 0.0 && config.process_noise >= 0.0;
+        if !noise_valid {
+            return Err(Status::invalid_argument(
+                "measurement_noise must be positive and process_noise must not be negative",
+            ));
+        }
+        let mut inner = self.inner.lock().unwrap();
+        (*inner).config = config;
+        Ok(Response::new(Empty {}))
+    }
+}
+
+impl SortMatcherState {
+    /// Start confirmed tracks from previously matched features, e.g. from a recorded track.
+    fn seed(&mut self, features_msg: Features) {
+        let config = self.config.clone();
+        self.tracks.clear();
+        self.released.clear();
+        for feature in features_msg.features.iter() {
+            let bounding_box = bounding_box(feature, config.min_box_size);
+            if let (Some(id), Some(bounding_box)) = (feature.id, bounding_box) {
+                let mut track = Track::new(&bounding_box, features_msg.frame_number, &config);
+                track.hits = track.hits.max(config.min_hits);
+                track.entity_id = Some(id);
+                self.tracks.push(track);
+            }
+        }
+    }
+
+    fn update(&mut self, entity_ids: Vec<u32>, mut features_msg: Features) -> Features {
+        let config = self.config.clone();
+        let frame_number = features_msg.frame_number;
+        let features = &mut features_msg.features;
+        for feature in features.iter_mut() {
+            feature.id = None;
+        }
+
+        // Entities may have been removed since the last frame
+        for track in self.tracks.iter_mut() {
+            if track.entity_id.is_some_and(|id| !entity_ids.contains(&id)) {
+                track.entity_id = None;
+            }
+        }
+        self.released.retain(|id, _| entity_ids.contains(id));
+
+        let boxes = features
+            .iter()
+            .map(|f| bounding_box(f, config.min_box_size))
+            .collect::<Vec<_>>();
+        let (high, low): (Vec<usize>, Vec<usize>) = (0..features.len())
+            .filter(|idx| boxes[*idx].is_some() && features[*idx].score >= config.low_threshold)
+            .partition(|idx| features[*idx].score >= config.high_threshold);
+        let predicted = self
+            .tracks
+            .iter()
+            .map(|track| track.predict(frame_number))
+            .collect::<Vec<_>>();
+
+        // Confident features are associated with all tracks, the remaining features with the
+        // tracks left over.
+        let all_tracks = (0..self.tracks.len()).collect::<Vec<_>>();
+        let high_associations = associate(
+            &high,
+            &all_tracks,
+            &boxes,
+            &predicted,
+            config.high_iou_threshold,
+        );
+        let remaining_tracks = all_tracks
+            .iter()
+            .copied()
+            .filter(|track_idx| !high_associations.iter().any(|(_, t)| t == track_idx))
+            .collect::<Vec<_>>();
+        let low_associations = associate(
+            &low,
+            &remaining_tracks,
+            &boxes,
+            &predicted,
+            config.low_iou_threshold,
+        );
+
+        let mut associations = high_associations;
+        associations.extend(low_associations);
+        for (feature_idx, track_idx) in associations.iter() {
+            self.tracks[*track_idx].update(&boxes[*feature_idx].unwrap(), frame_number);
+        }
+
+        // Confident features which were not associated start new tracks
+        for feature_idx in high.iter() {
+            let is_associated = associations.iter().any(|(f, _)| f == feature_idx);
+            if !is_associated && features[*feature_idx].score >= config.new_track_threshold {
+                let bounding_box = boxes[*feature_idx].unwrap();
+                associations.push((*feature_idx, self.tracks.len()));
+                self.tracks
+                    .push(Track::new(&bounding_box, frame_number, &config));
+            }
+        }
+
+        // Confirmed tracks receive a free entity id. Ids which were never held are used first,
+        // then the id which was released closest to the track.
+        let mut held = self
+            .tracks
+            .iter()
+            .filter_map(|track| track.entity_id)
+            .collect::<Vec<_>>();
+        for track in self.tracks.iter_mut() {
+            if track.entity_id.is_some() || track.hits < config.min_hits {
+                continue;
+            }
+            let (x, y) = track.center.position();
+            let free_id = entity_ids
+                .iter()
+                .filter(|id| !held.contains(id))
+                .min_by(|a, b| {
+                    let distance = |id: &u32| match self.released.get(id) {
+                        Some((released_x, released_y)) => {
+                            (released_x - x).powi(2) + (released_y - y).powi(2)
+                        }
+                        None => -1.0,
+                    };
+                    distance(a).total_cmp(&distance(b))
+                })
+                .copied();
+            if let Some(id) = free_id {
+                track.entity_id = Some(id);
+                self.released.remove(&id);
+                held.push(id);
+            }
+        }
+
+        let mut associated = vec![false; self.tracks.len()];
+        for (feature_idx, track_idx) in associations.iter() {
+            features[*feature_idx].id = self.tracks[*track_idx].entity_id;
+            associated[*track_idx] = true;
+        }
+
+        // Unconfirmed tracks are removed once they are missed, confirmed tracks after `max_age`
+        let mut track_idx = 0;
+        self.tracks.retain(|track| {
+            let keep = associated[track_idx]
+                || track.hits >= config.min_hits
+                    && frame_number.saturating_sub(track.frame_number) < config.max_age;
+            if !keep {
+                if let Some(id) = track.entity_id {
+                    self.released.insert(id, track.center.position());
+                }
+            }
+            track_idx += 1;
+            keep
+        });
+
+        features_msg
+    }
+}
+
+impl Track {
+    fn new(bounding_box: &BoundingBox, frame_number: u32, config: &SortMatcherConfig) -> Self {
+        let (x, y) = bounding_box.center();
+        let (width, height) = bounding_box.size();
+        Self {
+            center: KalmanFilter::new(x, y, config.process_noise, config.measurement_noise),
+            size: KalmanFilter::new(
+                width,
+                height,
+                config.process_noise,
+                config.measurement_noise,
+            ),
+            frame_number,
+            hits: 1,
+            entity_id: None,
+        }
+    }
+
+    fn predict(&self, frame_number: u32) -> BoundingBox {
+        let dt = frame_number.saturating_sub(self.frame_number) as f64;
+        let (x, y) = self.center.predict(dt);
+        let (width, height) = self.size.predict(dt);
+        BoundingBox::from_center(x, y, width.max(0.0), height.max(0.0))
+    }
+
+    fn update(&mut self, bounding_box: &BoundingBox, frame_number: u32) {
+        let dt = frame_number.saturating_sub(self.frame_number) as f64;
+        let (x, y) = bounding_box.center();
+        let (width, height) = bounding_box.size();
+        self.center.update(x, y, dt);
+        self.size.update(width, height, dt);
+        self.frame_number = frame_number;
+        self.hits += 1;
+    }
+}
+
+impl BoundingBox {
+    fn from_center(x: f64, y: f64, width: f64, height: f64) -> Self {
+        Self {
+            x1: x - width / 2.0,
+            y1: y - height / 2.0,
+            x2: x + width / 2.0,
+            y2: y + height / 2.0,
+        }
+    }
+
+    fn center(&self) -> (f64, f64) {
+        ((self.x1 + self.x2) / 2.0, (self.y1 + self.y2) / 2.0)
+    }
+
+    fn size(&self) -> (f64, f64) {
+        (self.x2 - self.x1, self.y2 - self.y1)
+    }
+
+    fn area(&self) -> f64 {
+        let (width, height) = self.size();
+        width * height
+    }
+
+    fn iou(&self, other: &BoundingBox) -> f64 {
+        let width = self.x2.min(other.x2) - self.x1.max(other.x1);
+        let height = self.y2.min(other.y2) - self.y1.max(other.y1);
+        if width <= 0.0 || height <= 0.0 {
+            return 0.0;
+        }
+        let intersection = width * height;
+        intersection / (self.area() + other.area() - intersection)
+    }
+}
+
+/// Axis aligned bounding box of the detected nodes, enlarged to at least `min_size`.
+fn bounding_box(feature: &Feature, min_size: f64) -> Option<BoundingBox> {
+    let mut nodes = feature
+        .image_nodes
+        .iter()
+        .filter(|node| !node.x.is_nan() && !node.y.is_nan())
+        .map(|node| (node.x as f64, node.y as f64));
+    let (x, y) = nodes.next()?;
+    let mut bounding_box = BoundingBox {
+        x1: x,
+        y1: y,
+        x2: x,
+        y2: y,
+    };
+    for (x, y) in nodes {
+        bounding_box.x1 = bounding_box.x1.min(x);
+        bounding_box.y1 = bounding_box.y1.min(y);
+        bounding_box.x2 = bounding_box.x2.max(x);
+        bounding_box.y2 = bounding_box.y2.max(y);
+    }
+    let (x, y) = bounding_box.center();
+    let (width, height) = bounding_box.size();
+    Some(BoundingBox::from_center(
+        x,
+        y,
+        width.max(min_size),
+        height.max(min_size),
+    ))
+}
+
+/// Optimal association of features with tracks by IoU. Pairs overlapping less than `min_iou` are
+/// not associated. Returns pairs of feature and track indices.
+fn associate(
+    feature_indices: &[usize],
+    track_indices: &[usize],
+    boxes: &[Option<BoundingBox>],
+    predicted: &[BoundingBox],
+    min_iou: f64,
+) -> Vec<(usize, usize)> {
+    let n = feature_indices.len().max(track_indices.len());
+    let mut costs = Matrix::new(n, n, IOU_SCALE as i64);
+    for (row, feature_idx) in feature_indices.iter().enumerate() {
+        let feature_box = boxes[*feature_idx].unwrap();
+        for (column, track_idx) in track_indices.iter().enumerate() {
+            let iou = feature_box.iou(&predicted[*track_idx]);
+            if iou >= min_iou {
+                costs[(row, column)] = ((1.0 - iou) * IOU_SCALE) as i64;
+            }
+        }
+    }
+    let (_, assignment) = kuhn_munkres_min(&costs);
+    assignment
+        .into_iter()
+        .enumerate()
+        .filter(|(row, column)| row < &feature_indices.len() && column < &track_indices.len())
+        .filter(|(row, column)| {
+            let feature_box = boxes[feature_indices[*row]].unwrap();
+            feature_box.iou(&predicted[track_indices[*column]]) >= min_iou
+        })
+        .map(|(row, column)| (feature_indices[row], track_indices[column]))
+        .collect()
+}
@@ -1,4 +1,5 @@
 use super::component::ComponentConnections;
+use super::entity_lifecycle::EntityLifecycle;
 use super::tracking::TrackingResult;
 use super::undistort::UndistortMap;
 use super::{arena::ArenaImpl, protocol::*, BiotrackerConfig, VideoDecoder, VideoEncoder};
@@ -15,6 +16,19 @@ pub struct State {
     pub undistortion: Option<UndistortMap>,
     pub arena_impl: ArenaImpl,
     pub connections: ComponentConnections,
+    /// Maximum number of frames in flight during tracking
+    pub tracking_pipeline_depth: usize,
+    /// Creates and retires entities automatically, if enabled
+    pub entity_lifecycle: Option<EntityLifecycle>,
+    /// Keep appearance embeddings in the track, they are large and only needed for matching
+    pub save_embeddings: bool,
+    /// Fail, if seeking or decoding an indexed video does not reach the expected frame
+    pub verify_seeks: bool,
+    /// The experiment changed apart from image and features, since it was last published
+    pub experiment_changed: bool,
+    /// Entities which are merged into the feature of another entity, and were not matched on their
+    /// own since
+    merged_ids: Vec<u32>,
     entity_counter: u32,
 }
 
@@ -44,12 +58,22 @@ impl State {
             },
             config,
             arena_impl: ArenaImpl::new(arena, &None).unwrap(),
+            tracking_pipeline_depth: 1,
             ..Default::default()
         }
     }
 
     pub fn handle_image_result(&mut self, image: Image) {
         self.experiment.last_image = Some(image.clone());
+        // Indexed videos know their exact frame count, once the index is built
+        if let (Some(decoder), Some(info)) = (&self.video_decoder, &mut self.experiment.video_info)
+        {
+            let frame_count = decoder.lock().unwrap().info.frame_count;
+            if info.frame_count != frame_count {
+                info.frame_count = frame_count;
+                self.experiment_changed = true;
+            }
+        }
         if !self.experiment.track_file.is_empty() {
             // If a track is loaded for replaying, search and immediately load tracking result.
             let frame_count = match &self.experiment.video_info {
@@ -74,15 +98,85 @@ impl State {
             mut features,
             skeleton,
         } = result;
-        self.experiment.skeleton = Some(skeleton.clone());
+        if self.experiment.skeleton.as_ref() != Some(&skeleton) {
+            self.experiment.skeleton = Some(skeleton.clone());
+            self.experiment_changed = true;
+        }
         metrics::counter!("count.detected_features", features.features.len() as u64);
         // Adjust the track frame numbers to start at 0
         let recording_frame_number = frame_number - self.track.original_track_start;
         features.frame_number = recording_frame_number;
+        let mut track_features = features.clone();
+        if !self.save_embeddings {
+            for feature in track_features.features.iter_mut() {
+                feature.embedding.clear();
+            }
+        }
         self.track
             .features
-            .insert(recording_frame_number, features.clone());
+            .insert(recording_frame_number, track_features);
         self.experiment.last_features = Some(features.clone());
+
+        let mut merged_ids = features
+            .features
+            .iter()
+            .flat_map(|f| f.merged_ids.iter().copied())
+            .collect::<Vec<_>>();
+        for id in merged_ids.iter() {
+            if !self.merged_ids.contains(id) {
+                self.track.entity_events.push(EntityEvent {
+                    frame_number: recording_frame_number,
+                    id: *id,
+                    event_type: EntityEventType::Merge as i32,
+                });
+            }
+        }
+        // Entities split, when they are matched on their own. Until then, they stay merged, also
+        // in frames which miss the shared feature.
+        for id in self.merged_ids.iter() {
+            if merged_ids.contains(id) {
+                continue;
+            }
+            if features.features.iter().any(|f| f.id == Some(*id)) {
+                self.track.entity_events.push(EntityEvent {
+                    frame_number: recording_frame_number,
+                    id: *id,
+                    event_type: EntityEventType::Split as i32,
+                });
+            } else if self.experiment.entity_ids.contains(id) {
+                merged_ids.push(*id);
+            }
+        }
+        self.merged_ids = merged_ids;
+
+        if let Some(lifecycle) = self.entity_lifecycle.as_mut() {
+            let changes = lifecycle.update(&features, &self.experiment.entity_ids);
+            if changes.births > 0 || !changes.deaths.is_empty() {
+                self.experiment_changed = true;
+            }
+            for _ in 0..changes.births {
+                self.entity_counter += 1;
+                let id = self.entity_counter;
+                self.experiment.entity_ids.push(id);
+                log::info!("Frame {} Created entity {}", recording_frame_number, id);
+                self.track.entity_events.push(EntityEvent {
+                    frame_number: recording_frame_number,
+                    id,
+                    event_type: EntityEventType::Birth as i32,
+                });
+            }
+            for id in changes.deaths {
+                self.experiment
+                    .entity_ids
+                    .retain(|entity_id| *entity_id != id);
+                log::info!("Frame {} Retired entity {}", recording_frame_number, id);
+                self.track.entity_events.push(EntityEvent {
+                    frame_number: recording_frame_number,
+                    id,
+                    event_type: EntityEventType::Death as i32,
+                });
+            }
+        }
     }
 
     pub fn open_video(
@@ -90,10 +184,15 @@ impl State {
         path: String,
         force_undistortion: &Option<String>,
     ) -> Result<VideoInfo> {
+        // Images stay in shared memory while they are tracked. Two additional buffers are reserved
+        // for the image currently decoded and the one displayed.
         let decoder = VideoDecoder::new(
             path,
             self.experiment.target_fps as f64,
             &self.config.cameras,
+            &self.experiment.arena,
+            self.verify_seeks,
+            self.tracking_pipeline_depth + 2,
         )?;
         let video_info = decoder.info.clone();
 
@@ -111,6 +210,7 @@ impl State {
         }
         let result = Ok(video_info.clone());
         self.experiment.video_info = Some(video_info);
+        self.experiment_changed = true;
         self.experiment.last_image = None;
         self.experiment.last_features = None;
         self.video_decoder = Some(Arc::new(Mutex::new(decoder)));
@@ -126,6 +226,7 @@ impl State {
             return Err(anyhow::anyhow!("No undistortion map configured"));
         }
         self.experiment.undistort_mode = mode as i32;
+        self.experiment_changed = true;
         Ok(())
     }
 
@@ -143,6 +244,7 @@ impl State {
             .map(|f| f.id.unwrap())
             .collect::<std::collections::HashSet<_>>();
         self.experiment.entity_ids = entities.into_iter().collect();
+        self.experiment_changed = true;
         self.track = track;
         Ok(())
     }
@@ -152,6 +254,16 @@ impl State {
         let file = std::fs::File::create(path)?;
         let writer = std::io::BufWriter::new(file);
         serde_json::to_writer(writer, &self.track)?;
+
+        // Simulated videos come with the ground truth, which is saved alongside for evaluation
+        if let Some(decoder) = &self.video_decoder {
+            let track_start = self.track.original_track_start;
+            if let Some(ground_truth) = decoder.lock().unwrap().ground_truth(track_start)? {
+                let path = std::path::Path::new(path).with_extension("ground_truth.json");
+                let writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+                serde_json::to_writer(writer, &ground_truth)?;
+            }
+        }
         Ok(())
     }
 
@@ -160,11 +272,13 @@ impl State {
         self.experiment.video_info = None;
         self.experiment.playback_state = PlaybackState::Eos as i32;
         self.experiment.recording_state = RecordingState::Initial as i32;
+        self.experiment_changed = true;
     }
 
     pub fn add_entity(&mut self) -> Result<()> {
         self.entity_counter += 1;
         self.experiment.entity_ids.push(self.entity_counter);
+        self.experiment_changed = true;
         Ok(())
     }
 
@@ -199,6 +313,34 @@ impl State {
         Ok(())
     }
 
+    /// Reset the matcher after a seek or a new video, so that entities are not matched to
+    /// positions from another point in time. If the track contains features for the frame
+    /// before `frame`, the matcher continues with their ids.
+    pub async fn reset_matcher(&mut self, frame: Option<u32>) -> Result<()> {
+        self.merged_ids.clear();
+        let mut matcher = match self.connections.matcher() {
+            Some(matcher) => matcher,
+            None => return Ok(()),
+        };
+        let seed = frame.and_then(|frame| {
+            let previous_frame = frame.checked_sub(1)?;
+            let track_frame_number = previous_frame.checked_sub(self.track.original_track_start)?;
+            let mut features = self.track.features.get(&track_frame_number)?.clone();
+            features.frame_number = previous_frame;
+            Some(features)
+        });
+        let mut result = matcher.reset(Empty {}).await.map(|_| ());
+        if let (Ok(_), Some(features)) = (&result, seed) {
+            result = matcher.seed(features).await.map(|_| ());
+        }
+        match result {
+            Ok(_) => Ok(()),
+            // Matchers without state do not need to implement reset and seed
+            Err(status) if status.code() == tonic::Code::Unimplemented => Ok(()),
+            Err(e) => Err(anyhow::anyhow!("Matcher failed to reset: {}", e)),
+        }
+    }
+
     pub fn start_recording(&mut self) -> Result<()> {
         let frame_start = match &self.experiment.last_image {
             Some(image) => image.frame_number,
@@ -213,6 +355,10 @@ impl State {
             original_track_start: frame_start,
             ..Default::default()
         };
+        self.merged_ids.clear();
+        if let Some(lifecycle) = self.entity_lifecycle.as_mut() {
+            lifecycle.reset();
+        }
         Ok(())
     }
 
@@ -228,36 +374,43 @@ impl State {
             _ => {}
         };
         self.experiment.recording_state = recording_state;
+        self.experiment_changed = true;
         Ok(())
     }
 
     pub fn initialize_recording(&mut self, config: RecordingConfig) -> Result<()> {
         let encoder = VideoEncoder::new(config.clone())?;
         self.experiment.recording_config = Some(config);
+        self.experiment_changed = true;
         self.video_encoder = Some(Arc::new(Mutex::new(encoder)));
         Ok(())
     }
 
     pub fn remove_entity(&mut self) -> Result<()> {
         self.experiment.entity_ids.pop();
+        self.experiment_changed = true;
         Ok(())
     }
 
-    pub fn seek(&mut self, frame: u32) -> Result<()> {
-        if let Some(decoder) = &mut self.video_decoder {
-            decoder.lock().unwrap().seek(frame)?;
+    /// Seeking decodes up to a whole group of pictures, so it runs outside of the runtime thread.
+    pub async fn seek(&mut self, frame: u32) -> Result<()> {
+        if let Some(decoder) = &self.video_decoder {
+            let decoder = decoder.clone();
+            tokio::task::spawn_blocking(move || decoder.lock().unwrap().seek(frame)).await??;
         }
         Ok(())
     }
 
     pub fn set_playback_state(&mut self, playback_state: i32) -> Result<()> {
         self.experiment.playback_state = playback_state;
+        self.experiment_changed = true;
         Ok(())
     }
 
     pub fn update_arena(&mut self, arena: Arena) -> Result<()> {
         self.arena_impl = ArenaImpl::new(arena.clone(), &self.experiment.video_info)?;
         self.experiment.arena = Some(arena);
+        self.experiment_changed = true;
         Ok(())
     }
 
@@ -265,6 +418,7 @@ impl State {
         for c in &mut self.experiment.components {
             if c.id == component.id {
                 *c = component;
+                self.experiment_changed = true;
                 return Ok(());
             }
         }
@@ -0,0 +1,160 @@
+use super::protocol::{Experiment, Features, Image, StateUpdate, SubscribeRequest};
+use futures::Stream;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::watch;
+use tonic::Status;
+
+pub type StateUpdateStream = Pin<Box<dyn Stream<Item = Result<StateUpdate, Status>> + Send>>;
+
+/// Publishes state changes of the core to subscribers. Image and features are published
+/// separately from the experiment, so that subscribers of the experiment are not updated on
+/// every frame.
+pub struct StatePublisher {
+    experiment_tx: watch::Sender<Experiment>,
+    image_tx: watch::Sender<Option<Image>>,
+    features_tx: watch::Sender<Option<Features>>,
+    /// The experiment changed, but was not yet sent, because nobody was subscribed
+    experiment_pending: AtomicBool,
+}
+
+impl StatePublisher {
+    pub fn new() -> Self {
+        Self {
+            experiment_tx: watch::channel(Experiment::default()).0,
+            image_tx: watch::channel(None).0,
+            features_tx: watch::channel(None).0,
+            experiment_pending: AtomicBool::new(true),
+        }
+    }
+
+    /// Called on every iteration of the core loop. Parts without subscribers are skipped, new
+    /// subscribers receive the current state with the next call. The experiment is only sent, if
+    /// `experiment_changed` was set on one of the calls since it was sent last.
+    pub fn publish(&self, experiment: &Experiment, experiment_changed: bool) {
+        if experiment_changed {
+            self.experiment_pending.store(true, Ordering::Relaxed);
+        }
+        if self.image_tx.receiver_count() > 0 {
+            send_if_changed(&self.image_tx, &experiment.last_image);
+        }
+        if self.features_tx.receiver_count() > 0 {
+            send_if_changed(&self.features_tx, &experiment.last_features);
+        }
+        if self.experiment_tx.receiver_count() > 0
+            && self.experiment_pending.swap(false, Ordering::Relaxed)
+        {
+            send_if_changed(&self.experiment_tx, &without_frame(experiment));
+        }
+    }
+
+    pub fn subscribe(&self, request: SubscribeRequest) -> StateUpdateStream {
+        let subscription = Subscription {
+            experiment: request.experiment.then(|| self.experiment_tx.subscribe()),
+            image: request.image.then(|| self.image_tx.subscribe()),
+            features: request.features.then(|| self.features_tx.subscribe()),
+            initial: true,
+        };
+        Box::pin(futures::stream::unfold(
+            subscription,
+            |mut subscription| async move {
+                let update = subscription.next_update().await?;
+                Some((Ok(update), subscription))
+            },
+        ))
+    }
+}
+
+struct Subscription {
+    experiment: Option<watch::Receiver<Experiment>>,
+    image: Option<watch::Receiver<Option<Image>>>,
+    features: Option<watch::Receiver<Option<Features>>>,
+    // The first update contains all requested parts
+    initial: bool,
+}
+
+impl Subscription {
+    /// Wait for the next change of any requested part. Returns None, if the core shut down.
+    async fn next_update(&mut self) -> Option<StateUpdate> {
+        loop {
+            let force = std::mem::take(&mut self.initial);
+            let mut update = StateUpdate::default();
+            let mut updated = false;
+            if let Some(experiment) = take_changed(&mut self.experiment, force)? {
+                update.experiment = Some(experiment);
+                updated = true;
+            }
+            if let Some(image) = take_changed(&mut self.image, force)? {
+                update.image_cleared = image.is_none();
+                update.image = image;
+                updated = true;
+            }
+            if let Some(features) = take_changed(&mut self.features, force)? {
+                update.features_cleared = features.is_none();
+                update.features = features;
+                updated = true;
+            }
+            if updated {
+                return Some(update);
+            }
+            tokio::select! {
+                result = wait_changed(&self.experiment) => result.ok()?,
+                result = wait_changed(&self.image) => result.ok()?,
+                result = wait_changed(&self.features) => result.ok()?,
+            }
+        }
+    }
+}
+
+/// The experiment without image and features, which are published separately and are too large
+/// to be cloned with every change of the experiment.
+fn without_frame(experiment: &Experiment) -> Experiment {
+    Experiment {
+        target_fps: experiment.target_fps,
+        video_info: experiment.video_info.clone(),
+        recording_config: experiment.recording_config.clone(),
+        arena: experiment.arena.clone(),
+        playback_state: experiment.playback_state,
+        recording_state: experiment.recording_state,
+        track_file: experiment.track_file.clone(),
+        realtime_mode: experiment.realtime_mode,
+        undistort_mode: experiment.undistort_mode,
+        last_image: None,
+        last_features: None,
+        entity_ids: experiment.entity_ids.clone(),
+        skeleton: experiment.skeleton.clone(),
+        components: experiment.components.clone(),
+        component_status: experiment.component_status.clone(),
+    }
+}
+
+fn send_if_changed<T: PartialEq + Clone>(tx: &watch::Sender<T>, value: &T) {
+    tx.send_if_modified(|current| {
+        if current == value {
+            return false;
+        }
+        *current = value.clone();
+        true
+    });
+}
+
+/// Returns the current value, if it changed since it was last taken. The outer Option is None, if
+/// the sender was dropped.
+fn take_changed<T: Clone>(rx: &mut Option<watch::Receiver<T>>, force: bool) -> Option<Option<T>> {
+    match rx {
+        Some(rx) => match rx.has_changed().ok()? || force {
+            true => Some(Some(rx.borrow_and_update().clone())),
+            false => Some(None),
+        },
+        None => Some(None),
+    }
+}
+
+/// Wait for a change, without marking it as seen. Pending forever for parts, which were not
+/// requested.
+async fn wait_changed<T>(rx: &Option<watch::Receiver<T>>) -> Result<(), watch::error::RecvError> {
+    match rx {
+        Some(rx) => rx.clone().changed().await,
+        None => futures::future::pending().await,
+    }
+}
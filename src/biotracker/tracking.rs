@@ -1,5 +1,9 @@
-use super::{arena::ArenaImpl, protocol::*, undistort::UndistortMap, State};
+use super::{
+    arena::ArenaImpl, component::ComponentError, protocol::*, undistort::UndistortMap, State,
+};
 use anyhow::{Context, Result};
+use std::collections::VecDeque;
+use tokio::sync::oneshot;
 
 #[derive(Debug)]
 pub struct TrackingResult {
@@ -8,6 +12,54 @@ pub struct TrackingResult {
     pub skeleton: SkeletonDescriptor,
 }
 
+/// Tracking tasks which are currently in flight. Feature detection runs concurrently for up to
+/// `depth` frames. Matching is chained from task to task, so that frames reach the matcher
+/// strictly in the order in which they were started, which is ascending by frame number.
+pub struct TrackingPipeline {
+    depth: usize,
+    tasks: VecDeque<tokio::task::JoinHandle<()>>,
+    last_frame_number: Option<u32>,
+    // Resolves, when the most recently started task finished matching.
+    last_matched: Option<oneshot::Receiver<()>>,
+}
+
+impl TrackingPipeline {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth: depth.max(1),
+            tasks: VecDeque::new(),
+            last_frame_number: None,
+            last_matched: None,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.tasks.len() >= self.depth
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Frame number of the most recently started tracking task.
+    pub fn last_frame_number(&self) -> Option<u32> {
+        self.last_frame_number
+    }
+
+    /// Tracking results are sent in order, so the oldest task is the one which finished.
+    pub fn task_finished(&mut self) {
+        self.tasks.pop_front();
+    }
+
+    pub fn abort(&mut self) {
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+        self.last_frame_number = None;
+        self.last_matched = None;
+    }
+}
+
 async fn tracking_task(
     image: Image,
     mut detector: FeatureDetectorClient<tonic::transport::Channel>,
@@ -15,11 +67,23 @@ async fn tracking_task(
     arena: ArenaImpl,
     entity_ids: Vec<u32>,
     undistortion: Option<UndistortMap>,
+    previous_matched: Option<oneshot::Receiver<()>>,
 ) -> Result<TrackingResult> {
-    let frame_number = image.frame_number;
+    let (frame_number, timestamp) = (image.frame_number, image.timestamp);
     let detector_start = std::time::Instant::now();
-    let response = detector.detect_features(image).await?.into_inner();
+    let response = detector.detect_features(image).await;
     metrics::histogram!("latency.feature_detector", detector_start.elapsed());
+    // Wait for the previous frame to be matched, even if detection failed. The previous task may
+    // also have been dropped, which is fine.
+    if let Some(previous_matched) = previous_matched {
+        let _ = previous_matched.await;
+    }
+    let response = response
+        .map_err(|status| ComponentError {
+            service_type: ServiceType::FeatureDetector,
+            status,
+        })?
+        .into_inner();
     let mut features = response
         .features
         .context("Received DetectorResponse without features")?;
@@ -27,14 +91,23 @@ async fn tracking_task(
         .skeleton
         .context("Received DetectorResponse without skeleton")?;
     features.frame_number = frame_number;
+    features.timestamp = timestamp;
     arena.features_to_world(&mut features, &skeleton, undistortion)?;
 
     let matcher_request = MatcherRequest {
         features: Some(features.clone()),
         entity_ids,
+        skeleton: Some(skeleton.clone()),
     };
     let matcher_start = std::time::Instant::now();
-    features = matcher.match_features(matcher_request).await?.into_inner();
+    features = matcher
+        .match_features(matcher_request)
+        .await
+        .map_err(|status| ComponentError {
+            service_type: ServiceType::Matcher,
+            status,
+        })?
+        .into_inner();
     metrics::histogram!("latency.matcher", matcher_start.elapsed());
     Ok(TrackingResult {
         frame_number,
@@ -43,31 +116,49 @@ async fn tracking_task(
     })
 }
 
+/// Start tracking an image. Returns false, if tracking is not possible, because the pipeline is
+/// full, a track is replayed, or components are missing.
 pub fn start_tracking_task(
     state: &State,
-    task_handle: &mut Option<tokio::task::JoinHandle<()>>,
+    pipeline: &mut TrackingPipeline,
     tracking_tx: &tokio::sync::mpsc::Sender<Result<TrackingResult>>,
     image: &Image,
-) {
-    if !state.experiment.track_file.is_empty() {
-        return;
+) -> bool {
+    if !state.experiment.track_file.is_empty() || pipeline.is_full() {
+        return false;
     }
     let start = std::time::Instant::now();
     let image = image.clone();
     let detector = state.connections.feature_detector();
     let matcher = state.connections.matcher();
     if detector.is_none() || matcher.is_none() {
-        return;
+        return false;
     }
     let (detector, matcher) = (detector.unwrap(), matcher.unwrap());
     let arena = state.arena_impl.clone();
     let tracking_tx = tracking_tx.clone();
     let entity_ids = state.experiment.entity_ids.clone();
     let undistortion = state.get_undistortion(UndistortMode::Poses);
-    *task_handle = Some(tokio::spawn(async move {
-        let result = tracking_task(image, detector, matcher, arena, entity_ids, undistortion).await;
+    let previous_matched = pipeline.last_matched.take();
+    let (matched_tx, matched_rx) = oneshot::channel();
+    pipeline.last_matched = Some(matched_rx);
+    pipeline.last_frame_number = Some(image.frame_number);
+    pipeline.tasks.push_back(tokio::spawn(async move {
+        let result = tracking_task(
+            image,
+            detector,
+            matcher,
+            arena,
+            entity_ids,
+            undistortion,
+            previous_matched,
+        )
+        .await;
         metrics::histogram!("latency.tracking", start.elapsed());
         metrics::increment_counter!("count.frame_tracked");
+        // Results are sent before the next task may continue, to keep them in order.
         tracking_tx.send(result).await.unwrap();
+        let _ = matched_tx.send(());
     }));
+    true
 }
@@ -11,7 +11,10 @@ mod util;
 
 fn main() -> anyhow::Result<()> {
     let args = CommandLineArguments::parse().canonicalize_paths()?;
-    let headless = args.headless;
+    if args.rematch.is_some() {
+        return biotracker::rematch::run(&args);
+    }
+    let headless = args.headless || args.batch_mode();
     cv::core::set_num_threads(args.cv_worker_threads as i32).unwrap();
 
     let config = BiotrackerConfig::load(&args.config)?;
@@ -55,21 +58,25 @@ fn main() -> anyhow::Result<()> {
             rt.block_on(async move {
                 match Core::new(&args, config).await {
                     Ok(mut core) => match core.run().await {
-                        Ok(_) => {}
+                        Ok(_) => Ok(()),
                         Err(e) => {
                             log::error!("Core failed: {}", e);
                             let _ = core.finish(&[]).await;
+                            Err(e)
                         }
                     },
                     Err(e) => {
                         println!("Failed to start BioTracker Core: {}", e);
+                        Err(e)
                     }
                 }
             })
         })?;
 
     if headless {
-        core_thread.join().unwrap();
+        if core_thread.join().unwrap().is_err() {
+            std::process::exit(1);
+        }
     } else {
         eframe::run_native(
             "BioTracker",
@@ -272,10 +272,16 @@ impl AnnotatedVideo {
             if n_nodes > 0 && self.draw_ids {
                 center_point.x /= n_nodes as f32;
                 center_point.y /= n_nodes as f32;
+                // Merged entities are labeled together with the entity they overlap
+                let label = std::iter::once(id)
+                    .chain(feature.merged_ids.iter().copied())
+                    .map(|id| id.to_string())
+                    .collect::<Vec<_>>()
+                    .join("+");
                 painter.text(
                     center_point,
                     egui::Align2::CENTER_TOP,
-                    id.to_string(),
+                    label,
                     egui::FontId {
                         size: text_size,
                         family: egui::FontFamily::Proportional,
@@ -50,7 +50,7 @@ pub struct BioTrackerUIComponents {
 pub struct BioTrackerUI {
     components: BioTrackerUIComponents,
     context: BioTrackerUIContext,
-    core_thread: Option<JoinHandle<()>>,
+    core_thread: Option<JoinHandle<anyhow::Result<()>>>,
     get_state_retry: Option<std::time::Instant>,
 }
 
@@ -58,7 +58,7 @@ impl BioTrackerUI {
     pub fn new(
         cc: &eframe::CreationContext,
         rt: Arc<tokio::runtime::Runtime>,
-        core_thread: JoinHandle<()>,
+        core_thread: JoinHandle<anyhow::Result<()>>,
         logger: &'static Logger,
         metrics: &'static MetricsRecorder,
         args: CommandLineArguments,
@@ -133,9 +133,8 @@ impl BioTrackerUI {
                 return;
             }
         }
-        match self.context.bt.get_state() {
-            Ok(state) => {
-                self.context.experiment = state;
+        match self.context.bt.update_state(&mut self.context.experiment) {
+            Ok(_) => {
                 self.update_image(frame);
             }
             Err(e) => {
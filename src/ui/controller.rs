@@ -1,10 +1,35 @@
 use crate::biotracker::protocol::*;
 use anyhow::Result;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 pub struct BioTrackerController {
     client: bio_tracker_client::BioTrackerClient<tonic::transport::Channel>,
     rt: Arc<tokio::runtime::Runtime>,
+    subscription: Arc<Mutex<Subscription>>,
+}
+
+/// State updates received from the core, which were not yet applied to the UI.
+#[derive(Default)]
+struct Subscription {
+    pending: StateUpdate,
+    error: Option<String>,
+}
+
+impl Subscription {
+    fn merge(&mut self, update: StateUpdate) {
+        if update.experiment.is_some() {
+            self.pending.experiment = update.experiment;
+        }
+        // Cleared parts replace earlier values, which were not applied yet
+        if update.image.is_some() || update.image_cleared {
+            self.pending.image = update.image;
+            self.pending.image_cleared = update.image_cleared;
+        }
+        if update.features.is_some() || update.features_cleared {
+            self.pending.features = update.features;
+            self.pending.features_cleared = update.features_cleared;
+        }
+    }
 }
 
 impl BioTrackerController {
@@ -21,16 +46,62 @@ impl BioTrackerController {
                 return Err(anyhow::anyhow!("Failed to connect to BioTracker Core"));
             })
             .unwrap();
-        Self { client, rt }
+        let mut controller = Self {
+            client,
+            rt,
+            subscription: Arc::new(Mutex::new(Subscription::default())),
+        };
+        controller.subscribe();
+        controller
     }
 
-    pub fn get_state(&mut self) -> Result<Experiment> {
-        let BioTrackerController { client, rt } = self;
-        let response = rt.block_on(async move {
-            let request = tonic::Request::new(Empty {});
-            client.get_state(request).await
+    fn subscribe(&mut self) {
+        let mut client = self.client.clone();
+        let subscription = self.subscription.clone();
+        self.rt.spawn(async move {
+            let request = SubscribeRequest {
+                experiment: true,
+                image: true,
+                features: true,
+            };
+            let result = async {
+                let mut stream = client.subscribe(request).await?.into_inner();
+                while let Some(update) = stream.message().await? {
+                    subscription.lock().unwrap().merge(update);
+                }
+                Ok::<(), tonic::Status>(())
+            }
+            .await;
+            subscription.lock().unwrap().error = Some(match result {
+                Ok(_) => "State subscription ended".to_owned(),
+                Err(e) => e.message().to_owned(),
+            });
         });
-        Ok(response?.into_inner())
+    }
+
+    /// Apply state updates pushed by the core since the last call. If the subscription failed,
+    /// the error is returned and the core is subscribed again.
+    pub fn update_state(&mut self, experiment: &mut Experiment) -> Result<()> {
+        let mut subscription = self.subscription.lock().unwrap();
+        if let Some(error) = subscription.error.take() {
+            drop(subscription);
+            self.subscribe();
+            return Err(anyhow::anyhow!(error));
+        }
+        let update = std::mem::take(&mut subscription.pending);
+        if let Some(mut new_experiment) = update.experiment {
+            // Image and features are not part of experiment updates
+            new_experiment.last_image = experiment.last_image.take();
+            new_experiment.last_features = experiment.last_features.take();
+            *experiment = new_experiment;
+        }
+        if update.image.is_some() || update.image_cleared {
+            experiment.last_image = update.image;
+        }
+        if update.features.is_some() || update.features_cleared {
+            experiment.last_features = update.features;
+        }
+        Ok(())
     }
 
     pub fn command(&mut self, command: Command) {
@@ -46,7 +117,7 @@ impl BioTrackerController {
         &mut self,
         command: Command,
     ) -> Result<tonic::Response<Empty>, tonic::Status> {
-        let BioTrackerController { client, rt } = self;
+        let BioTrackerController { client, rt, .. } = self;
         let response = rt.block_on(async move {
             let biotracker_command = BioTrackerCommand {
                 command: Some(command),
@@ -58,7 +129,7 @@ impl BioTrackerController {
     }
 
     pub fn add_image(&mut self, image: Image) -> Result<Empty> {
-        let BioTrackerController { client, rt } = self;
+        let BioTrackerController { client, rt, .. } = self;
         let response = rt.block_on(async move {
             let request = tonic::Request::new(image);
             client.add_image(request).await
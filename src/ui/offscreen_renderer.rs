@@ -187,6 +187,7 @@ impl OffscreenRenderer {
                 height: self.texture.size.height,
                 frame_number,
                 channels: 3,
+                timestamp: None,
             };
             self.copy_buffer = None;
             return Ok(image);
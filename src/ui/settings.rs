@@ -61,6 +61,13 @@ pub fn file_open_buttons(ui: &mut egui::Ui, ctx: &mut BioTrackerUIContext) {
     if ui.button("🎬").on_hover_text("Open video").clicked() {
         open_video(ctx);
     }
+    if ui
+        .button("🖼")
+        .on_hover_text("Open image sequence")
+        .clicked()
+    {
+        open_image_sequence(ctx);
+    }
     ui.menu_button("🖭", |ui| {
         if ui.button("Load Track").clicked() {
             if let Some(path) = file_open_menu() {
@@ -224,6 +231,26 @@ pub fn recording_settings(ui: &mut egui::Ui, ctx: &mut BioTrackerUIContext) {
     ui.end_row();
 }
 
+pub fn component_status(ui: &mut egui::Ui, ctx: &mut BioTrackerUIContext) {
+    for status in &ctx.experiment.component_status {
+        let (color, state) = match ComponentState::from_i32(status.state) {
+            Some(ComponentState::Ready) => (egui::Color32::GREEN, "Ready"),
+            Some(ComponentState::Starting) => (egui::Color32::YELLOW, "Starting"),
+            Some(ComponentState::Restarting) => (egui::Color32::YELLOW, "Restarting"),
+            _ => (egui::Color32::RED, "Failed"),
+        };
+        ui.label(&status.id);
+        let response = ui.colored_label(color, format!("● {}", state));
+        if !status.message.is_empty() || status.restarts > 0 {
+            response.on_hover_text(format!(
+                "{} (restarts: {})",
+                status.message, status.restarts
+            ));
+        }
+        ui.end_row();
+    }
+}
+
 pub fn settings_window(
     ui: &mut egui::Ui,
     ctx: &mut BioTrackerUIContext,
@@ -261,6 +288,11 @@ pub fn settings_window(
                     ui.end_row();
                     recording_settings(ui, ctx);
 
+                    ui.heading("Components");
+                    ui.separator();
+                    ui.end_row();
+                    component_status(ui, ctx);
+
                     for component in ctx.experiment.components.iter_mut() {
                         if component.id != "HungarianMatcher" {
                             continue;
@@ -314,6 +346,14 @@ pub fn open_video(ctx: &mut BioTrackerUIContext) {
     }
 }
 
+pub fn open_image_sequence(ctx: &mut BioTrackerUIContext) {
+    if let Some(path) = folder_open_menu() {
+        ctx.bt
+            .command(Command::OpenVideo(format!("images://{}", path)));
+        ctx.bt.command(Command::Seek(0));
+    }
+}
+
 fn undistort_description(mode: &UndistortMode) -> &str {
     match mode {
         UndistortMode::None => "No undistortion",